@@ -0,0 +1,52 @@
+//! Times `eksempler/fizzbuzz.æs` counting to a much bigger number than 100.
+//!
+//! Run with `cargo bench`. To compare against another build of the interpreter, for example one
+//! built from an older commit, point `AE_SKARP_BASELINE` at its binary:
+//!
+//! ```text
+//! AE_SKARP_BASELINE=/tmp/ae-skarp-old cargo bench
+//! ```
+
+use std::env;
+use std::fs;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const COUNT_TO: u32 = 300_000;
+const RUNS: usize = 7;
+
+fn main() {
+    let source = fs::read_to_string("eksempler/fizzbuzz.æs").expect("could not read fizzbuzz.æs");
+    let scaled = source.replacen("100", &COUNT_TO.to_string(), 1);
+    assert_ne!(scaled, source, "fizzbuzz.æs no longer counts to 100");
+    let path = env::temp_dir().join("ae-skarp-bench-fizzbuzz.æs");
+    fs::write(&path, scaled).expect("could not write the scaled program");
+    let path = path.to_str().unwrap();
+
+    println!("fizzbuzz to {}, best and median of {} runs", COUNT_TO, RUNS);
+    let new = bench("ae-skarp", env!("CARGO_BIN_EXE_ae-skarp"), path);
+    if let Ok(baseline) = env::var("AE_SKARP_BASELINE") {
+        let old = bench("baseline", &baseline, path);
+        println!("speedup: {:.2}x", old.as_secs_f64() / new.as_secs_f64());
+    }
+}
+
+/// Runs `program` with `binary` `RUNS` times and returns the median time.
+fn bench(name: &str, binary: &str, program: &str) -> Duration {
+    let mut times: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            let status = Command::new(binary)
+                .arg(program)
+                .stdout(Stdio::null())
+                .status()
+                .unwrap_or_else(|err| panic!("could not run {}: {}", binary, err));
+            assert!(status.success(), "{} failed", binary);
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    let median = times[RUNS / 2];
+    println!("{:>10}: {:>8.1?} {:>8.1?}", name, times[0], median);
+    median
+}
@@ -0,0 +1,113 @@
+use crate::utils::*;
+use std::fmt::{self, Write};
+
+/// A node of the tree the parser builds. Control flow is nested here, and only gets turned into
+/// jumps when the tree is lowered to a flat list of instructions.
+pub struct Ast {
+    pub kind: AstKind,
+    pub span: Span,
+}
+
+pub enum AstKind {
+    /// An instruction without any control flow, like pushing a value or running an operator.
+    Op(Node),
+    Call(String),
+    Block(Vec<Ast>),
+    If {
+        condition: Vec<Ast>,
+        then: Vec<Ast>,
+        else_ifs: Vec<ElseIf>,
+        otherwise: Option<Vec<Ast>>,
+    },
+    While {
+        condition: Vec<Ast>,
+        body: Vec<Ast>,
+    },
+    /// Loops over the iterable on top of the stack, storing every element in `slot`.
+    For {
+        name: String,
+        slot: Slot,
+        body: Vec<Ast>,
+    },
+    Function {
+        name: String,
+        body: Vec<Ast>,
+    },
+    /// A `test "navn" { .. }` block. It is left out of normal runs, and `ae-skarp test` runs it after
+    /// the rest of the file.
+    Test {
+        name: String,
+        body: Vec<Ast>,
+    },
+}
+
+pub struct ElseIf {
+    pub condition: Vec<Ast>,
+    pub body: Vec<Ast>,
+    pub span: Span,
+}
+
+impl Ast {
+    pub fn new(kind: AstKind, span: Span) -> Ast {
+        Ast { kind, span }
+    }
+}
+
+/// Writes the tree as indented text for the `-d` flag.
+pub fn dump(ast: &[Ast]) -> String {
+    let mut out = String::new();
+    dump_into(&mut out, ast, 0).expect("writing to a String can not fail");
+    out
+}
+
+fn dump_into(out: &mut String, ast: &[Ast], depth: usize) -> fmt::Result {
+    let pad = "  ".repeat(depth);
+    for node in ast {
+        let span = node.span;
+        match &node.kind {
+            AstKind::Op(op) => writeln!(out, "{}{:?} ({})", pad, op, span)?,
+            AstKind::Call(name) => writeln!(out, "{}Call({}) ({})", pad, name, span)?,
+            AstKind::Block(body) => {
+                writeln!(out, "{}Block ({})", pad, span)?;
+                dump_into(out, body, depth + 1)?;
+            }
+            AstKind::If { condition, then, else_ifs, otherwise } => {
+                writeln!(out, "{}If ({})", pad, span)?;
+                dump_part(out, "condition", condition, depth + 1)?;
+                dump_part(out, "then", then, depth + 1)?;
+                for else_if in else_ifs {
+                    writeln!(out, "{}ElseIf ({})", pad, else_if.span)?;
+                    dump_part(out, "condition", &else_if.condition, depth + 1)?;
+                    dump_part(out, "then", &else_if.body, depth + 1)?;
+                }
+                if let Some(otherwise) = otherwise {
+                    writeln!(out, "{}Else", pad)?;
+                    dump_into(out, otherwise, depth + 1)?;
+                }
+            }
+            AstKind::While { condition, body } => {
+                writeln!(out, "{}While ({})", pad, span)?;
+                dump_part(out, "condition", condition, depth + 1)?;
+                dump_part(out, "body", body, depth + 1)?;
+            }
+            AstKind::For { name, slot, body } => {
+                writeln!(out, "{}For({}@{}) ({})", pad, name, slot, span)?;
+                dump_into(out, body, depth + 1)?;
+            }
+            AstKind::Function { name, body } => {
+                writeln!(out, "{}Function({}) ({})", pad, name, span)?;
+                dump_into(out, body, depth + 1)?;
+            }
+            AstKind::Test { name, body } => {
+                writeln!(out, "{}Test({:?}) ({})", pad, name, span)?;
+                dump_into(out, body, depth + 1)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn dump_part(out: &mut String, label: &str, ast: &[Ast], depth: usize) -> fmt::Result {
+    writeln!(out, "{}{}:", "  ".repeat(depth), label)?;
+    dump_into(out, ast, depth + 1)
+}
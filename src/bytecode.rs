@@ -0,0 +1,488 @@
+use crate::utils::*;
+use anyhow::{anyhow, bail, Result};
+
+/// The first bytes of every `.æsb` file.
+pub const MAGIC: &[u8; 4] = b"AESB";
+/// Bumped every time the layout of the file or the meaning of an instruction changes, so files
+/// compiled by an older version are rejected instead of running the wrong code.
+pub const VERSION: u16 = 1;
+
+const HEADER_LEN: usize = MAGIC.len() + 2 + 8;
+
+/// Writes compiled code as an `.æsb` file. The layout is the magic bytes, the version as a
+/// little-endian `u16`, an FNV-1a checksum of the rest of the file as a `u64`, and then the
+/// instructions.
+pub fn encode(code: &[Instr]) -> Vec<u8> {
+    let mut body = Writer { bytes: vec![] };
+    body.usize(code.len());
+    for instr in code {
+        body.instr(instr);
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(VERSION.to_le_bytes());
+    bytes.extend(checksum(&body.bytes).to_le_bytes());
+    bytes.extend(body.bytes);
+    bytes
+}
+
+/// Whether `bytes` look like an `.æsb` file rather than source code.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Reads code written by `encode`, so it can be run without parsing it again. The code is not type
+/// checked, so `eval` reports wrong values as errors instead of trusting the parser.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Instr>> {
+    if !is_bytecode(bytes) {
+        bail!("not a bytecode file");
+    }
+    if bytes.len() < HEADER_LEN {
+        bail!("bytecode file ends in the middle of the header");
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+        bail!(
+            "bytecode version {} is not supported, expected version {}. Compile the program again with --emit-bytecode",
+            version,
+            VERSION
+        );
+    }
+    let expected = u64::from_le_bytes(bytes[6..HEADER_LEN].try_into().unwrap());
+    let body = &bytes[HEADER_LEN..];
+    if checksum(body) != expected {
+        bail!("bytecode checksum does not match, the file is damaged");
+    }
+
+    let mut reader = Reader { bytes: body, pos: 0 };
+    let len = reader.usize()?;
+    let mut code = vec![];
+    for _ in 0..len {
+        code.push(reader.instr()?);
+    }
+    if reader.pos != body.len() {
+        bail!("bytecode file has {} bytes left after the last instruction", body.len() - reader.pos);
+    }
+    // a jump out of the code would quietly end the program instead of failing
+    for (i, instr) in code.iter().enumerate() {
+        let Some(pointer) = instr.node.pointer() else { continue };
+        if pointer.resolve(i).wrapping_add(1) > code.len() {
+            bail!("instruction {} in bytecode file jumps outside of the code", i);
+        }
+    }
+    // every variable gets its slot from an instruction that declares it, so there are fewer slots
+    // than instructions, and a bigger slot would make eval allocate memory for variables that do
+    // not exist
+    for (i, instr) in code.iter().enumerate() {
+        let Some(Slot::Global(slot) | Slot::Local(slot)) = instr.node.slot() else { continue };
+        if slot >= code.len() {
+            bail!("instruction {} in bytecode file uses variable slot {}, which is not declared", i, slot);
+        }
+    }
+    Ok(code)
+}
+
+/// 64-bit FNV-1a hash.
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+mod tag {
+    pub const PUSH_INT: u8 = 0;
+    pub const PUSH_FLOAT: u8 = 1;
+    pub const PUSH_BOOL: u8 = 2;
+    pub const PUSH_STR: u8 = 3;
+    pub const OPERATOR: u8 = 4;
+    pub const LOAD: u8 = 5;
+    pub const JUMP: u8 = 6;
+    pub const JUMP_IF_FALSE: u8 = 7;
+    pub const END_OF_IF: u8 = 8;
+    pub const STORE: u8 = 9;
+    pub const FUNCTION: u8 = 10;
+    pub const CALL: u8 = 11;
+    pub const FUNC_RETURN: u8 = 12;
+    pub const MAKE_LIST: u8 = 13;
+    pub const REPEAT_LIST: u8 = 14;
+    pub const INDEX: u8 = 15;
+    pub const SET_INDEX: u8 = 16;
+    pub const POP_LIST: u8 = 17;
+    pub const ITER_START: u8 = 18;
+    pub const ITER_NEXT: u8 = 19;
+    pub const CALL_HOST: u8 = 20;
+    pub const LOAD_INDEX: u8 = 21;
+    pub const LOAD_LEN: u8 = 22;
+    pub const APPEND: u8 = 23;
+    pub const CLEAR: u8 = 24;
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, x: u8) {
+        self.bytes.push(x);
+    }
+
+    fn u64(&mut self, x: u64) {
+        self.bytes.extend(x.to_le_bytes());
+    }
+
+    fn usize(&mut self, x: usize) {
+        self.u64(x as u64);
+    }
+
+    fn str(&mut self, x: &str) {
+        self.usize(x.len());
+        self.bytes.extend(x.as_bytes());
+    }
+
+    fn slot(&mut self, slot: &Slot) {
+        match slot {
+            Slot::Global(i) => {
+                self.u8(0);
+                self.usize(*i);
+            }
+            Slot::Local(i) => {
+                self.u8(1);
+                self.usize(*i);
+            }
+        }
+    }
+
+    fn pointer(&mut self, pointer: &JumpPointer) {
+        self.u64(pointer.offset() as i64 as u64);
+    }
+
+    fn instr(&mut self, instr: &Instr) {
+        self.usize(instr.span.line);
+        self.usize(instr.span.col);
+        match &instr.node {
+            Node::PushInt(x) => {
+                self.u8(tag::PUSH_INT);
+                self.u64(*x as u64);
+            }
+            Node::PushFloat(x) => {
+                self.u8(tag::PUSH_FLOAT);
+                self.u64(x.to_bits());
+            }
+            Node::PushBool(x) => {
+                self.u8(tag::PUSH_BOOL);
+                self.u8(*x as u8);
+            }
+            Node::PushStr(x) => {
+                self.u8(tag::PUSH_STR);
+                self.str(x);
+            }
+            Node::Operator(op) => {
+                self.u8(tag::OPERATOR);
+                self.u8(*op as u8);
+            }
+            Node::Load(name, slot) => {
+                self.u8(tag::LOAD);
+                self.str(name);
+                self.slot(slot);
+            }
+            Node::Jump(pointer) => {
+                self.u8(tag::JUMP);
+                self.pointer(pointer);
+            }
+            Node::JumpIfFalse(pointer) => {
+                self.u8(tag::JUMP_IF_FALSE);
+                self.pointer(pointer);
+            }
+            Node::EndOfIf => self.u8(tag::END_OF_IF),
+            Node::Store(name, slot) => {
+                self.u8(tag::STORE);
+                self.str(name);
+                self.slot(slot);
+            }
+            Node::Function(name, pointer) => {
+                self.u8(tag::FUNCTION);
+                self.str(name);
+                self.pointer(pointer);
+            }
+            Node::Call(name) => {
+                self.u8(tag::CALL);
+                self.str(name);
+            }
+            Node::CallHost(name) => {
+                self.u8(tag::CALL_HOST);
+                self.str(name);
+            }
+            Node::FuncReturn => self.u8(tag::FUNC_RETURN),
+            Node::MakeList(len) => {
+                self.u8(tag::MAKE_LIST);
+                self.usize(*len);
+            }
+            Node::RepeatList(len) => {
+                self.u8(tag::REPEAT_LIST);
+                self.usize(*len);
+            }
+            Node::Index => self.u8(tag::INDEX),
+            Node::LoadIndex(name, slot) => {
+                self.u8(tag::LOAD_INDEX);
+                self.str(name);
+                self.slot(slot);
+            }
+            Node::LoadLen(name, slot) => {
+                self.u8(tag::LOAD_LEN);
+                self.str(name);
+                self.slot(slot);
+            }
+            Node::Append(name, slot) => {
+                self.u8(tag::APPEND);
+                self.str(name);
+                self.slot(slot);
+            }
+            Node::SetIndex(name, slot) => {
+                self.u8(tag::SET_INDEX);
+                self.str(name);
+                self.slot(slot);
+            }
+            Node::PopList => self.u8(tag::POP_LIST),
+            Node::IterStart => self.u8(tag::ITER_START),
+            Node::IterNext(name, slot, pointer) => {
+                self.u8(tag::ITER_NEXT);
+                self.str(name);
+                self.slot(slot);
+                self.pointer(pointer);
+            }
+            Node::Clear(name, slot) => {
+                self.u8(tag::CLEAR);
+                self.str(name);
+                self.slot(slot);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(n))
+            .ok_or_else(|| anyhow!("bytecode file ends in the middle of an instruction"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize> {
+        let x = self.u64()?;
+        usize::try_from(x).map_err(|_| anyhow!("number {} in bytecode file is too big", x))
+    }
+
+    fn str(&mut self) -> Result<String> {
+        let len = self.usize()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| anyhow!("string in bytecode file is not valid UTF-8"))
+    }
+
+    fn slot(&mut self) -> Result<Slot> {
+        match self.u8()? {
+            0 => Ok(Slot::Global(self.usize()?)),
+            1 => Ok(Slot::Local(self.usize()?)),
+            x => bail!("unknown slot kind {} in bytecode file", x),
+        }
+    }
+
+    fn pointer(&mut self) -> Result<JumpPointer> {
+        Ok(JumpPointer::new(self.u64()? as i64 as isize))
+    }
+
+    fn instr(&mut self) -> Result<Instr> {
+        let span = Span::new(self.usize()?, self.usize()?);
+        let node = match self.u8()? {
+            tag::PUSH_INT => Node::PushInt(self.u64()? as i64),
+            tag::PUSH_FLOAT => Node::PushFloat(f64::from_bits(self.u64()?)),
+            tag::PUSH_BOOL => Node::PushBool(self.u8()? != 0),
+            tag::PUSH_STR => Node::PushStr(self.str()?),
+            tag::OPERATOR => {
+                let x = self.u8()?;
+                let op = Op::ALL
+                    .get(x as usize)
+                    .ok_or_else(|| anyhow!("unknown operator {} in bytecode file", x))?;
+                Node::Operator(*op)
+            }
+            tag::LOAD => Node::Load(self.str()?, self.slot()?),
+            tag::JUMP => Node::Jump(self.pointer()?),
+            tag::JUMP_IF_FALSE => Node::JumpIfFalse(self.pointer()?),
+            tag::END_OF_IF => Node::EndOfIf,
+            tag::STORE => Node::Store(self.str()?, self.slot()?),
+            tag::FUNCTION => Node::Function(self.str()?, self.pointer()?),
+            tag::CALL => Node::Call(self.str()?),
+            tag::CALL_HOST => Node::CallHost(self.str()?),
+            tag::FUNC_RETURN => Node::FuncReturn,
+            tag::MAKE_LIST => Node::MakeList(self.usize()?),
+            tag::REPEAT_LIST => Node::RepeatList(self.usize()?),
+            tag::INDEX => Node::Index,
+            tag::LOAD_INDEX => Node::LoadIndex(self.str()?, self.slot()?),
+            tag::LOAD_LEN => Node::LoadLen(self.str()?, self.slot()?),
+            tag::APPEND => Node::Append(self.str()?, self.slot()?),
+            tag::SET_INDEX => Node::SetIndex(self.str()?, self.slot()?),
+            tag::POP_LIST => Node::PopList,
+            tag::ITER_START => Node::IterStart,
+            tag::ITER_NEXT => Node::IterNext(self.str()?, self.slot()?, self.pointer()?),
+            tag::CLEAR => Node::Clear(self.str()?, self.slot()?),
+            x => bail!("unknown instruction {} in bytecode file", x),
+        };
+        Ok(Instr::new(node, span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lower, parser::Parser, Interpreter, Program};
+
+    fn compile(source: &str) -> Vec<Instr> {
+        lower::lower(Parser::parse(source, None).unwrap().ast)
+    }
+
+    fn dump(code: &[Instr]) -> Vec<String> {
+        code.iter().map(|instr| format!("{:?} ({})", instr, instr.span)).collect()
+    }
+
+    /// Runs `code` from a bytecode file and returns what it wrote, or the runtime error.
+    fn run(code: &[Instr]) -> Result<String, RuntimeErrorKind> {
+        let mut interpreter = Interpreter::new();
+        interpreter.capture_output();
+        let program = Program::from_bytecode(&encode(code)).unwrap();
+        interpreter.run(program).map_err(|err| err.downcast::<RuntimeError>().unwrap().kind)?;
+        Ok(interpreter.take_output())
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = "funk dobbel Htall -> Htall { 2 * }\n\
+            var xs = [1, 2, 3]\n\
+            xs[0] = 1.5 til_htall\n\
+            for hver x i xs { x dobbel skrivnl }\n\
+            hvis xs lengde 3 == { \"æøå\" skrivnl } ellers { sann skrivnl }";
+        let code = compile(source);
+        let decoded = decode(&encode(&code)).unwrap();
+        assert_eq!(dump(&decoded), dump(&code));
+        assert_eq!(run(&decoded).unwrap(), "2\n4\n6\næøå\n");
+    }
+
+    #[test]
+    fn damaged_file() {
+        let mut bytes = encode(&compile("1 2 + skrivnl"));
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let err = decode(&bytes).unwrap_err();
+        assert!(err.to_string().contains("checksum does not match"), "{}", err);
+    }
+
+    #[test]
+    fn other_version() {
+        let mut bytes = encode(&compile("1 skrivnl"));
+        bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let err = decode(&bytes).unwrap_err();
+        assert!(err.to_string().contains(&format!("version {} is not supported", VERSION + 1)), "{}", err);
+    }
+
+    #[test]
+    fn jump_outside_of_code() {
+        let span = Span::new(1, 1);
+        let code = vec![Instr::new(Node::Jump(JumpPointer::new(5)), span)];
+        assert!(decode(&encode(&code)).is_err());
+    }
+
+    #[test]
+    fn error_at_line_and_column_zero() {
+        let code = vec![
+            Instr::new(Node::PushInt(1), Span::new(0, 0)),
+            Instr::new(Node::PushInt(0), Span::new(0, 0)),
+            Instr::new(Node::Operator(Op::DivInt), Span::new(0, 0)),
+        ];
+        let err = Interpreter::new().run(Program::from_bytecode(&encode(&code)).unwrap()).unwrap_err();
+        let rendered = render_error(&err, "fil.æsb", "1 0 /").unwrap();
+        assert_eq!(rendered, "error: division by zero\n --> fil.æsb:0:0\n");
+
+        let code = vec![Instr::new(Node::PushInt(1), Span::new(1, 0)), Instr::new(Node::PopList, Span::new(1, 0))];
+        let err = Interpreter::new().run(Program::from_bytecode(&encode(&code)).unwrap()).unwrap_err();
+        let rendered = render_error(&err, "fil.æsb", "1 ta_ut").unwrap();
+        assert!(rendered.ends_with("1 | 1 ta_ut\n  | ^\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn slot_outside_of_code() {
+        let span = Span::new(1, 1);
+        let code = vec![
+            Instr::new(Node::PushInt(1), span),
+            Instr::new(Node::Store("x".to_string(), Slot::Global(usize::MAX)), span),
+        ];
+        let err = decode(&encode(&code)).unwrap_err();
+        assert!(err.to_string().contains("uses variable slot"), "{}", err);
+    }
+
+    #[test]
+    fn unchecked_code_fails_without_panicking() {
+        let span = Span::new(1, 1);
+        let index_int = vec![
+            Instr::new(Node::PushInt(1), span),
+            Instr::new(Node::PushInt(2), span),
+            Instr::new(Node::Index, span),
+        ];
+        let code = decode(&encode(&index_int)).unwrap();
+        assert!(matches!(run(&code), Err(RuntimeErrorKind::WrongType { .. })));
+
+        let local_store = vec![
+            Instr::new(Node::PushInt(1), span),
+            Instr::new(Node::Store("x".to_string(), Slot::Local(0)), span),
+        ];
+        let code = decode(&encode(&local_store)).unwrap();
+        assert_eq!(run(&code), Err(RuntimeErrorKind::LocalOutsideFunction("x".to_string())));
+
+        let huge_list = vec![Instr::new(Node::PushInt(0), span), Instr::new(Node::RepeatList(usize::MAX / 2), span)];
+        let code = decode(&encode(&huge_list)).unwrap();
+        assert_eq!(run(&code), Err(RuntimeErrorKind::ListTooLong(usize::MAX / 2)));
+    }
+
+    #[test]
+    fn operands_of_the_wrong_type() {
+        let span = Span::new(1, 1);
+        let sum_int_str = vec![
+            Instr::new(Node::PushInt(1), span),
+            Instr::new(Node::PushStr("a".to_string()), span),
+            Instr::new(Node::Operator(Op::SumInt), span),
+            Instr::new(Node::PushInt(7), span),
+        ];
+        let code = decode(&encode(&sum_int_str)).unwrap();
+        assert_eq!(run(&code), Err(RuntimeErrorKind::WrongType {
+            op: "SumInt".to_string(),
+            found: vec![Value::Int(1), Value::Str("a".to_string())],
+        }));
+
+        let loop_over_int = vec![
+            Instr::new(Node::PushInt(3), span),
+            Instr::new(Node::IterStart, span),
+            Instr::new(Node::IterNext("x".to_string(), Slot::Global(0), JumpPointer::new(1)), span),
+            Instr::new(Node::Jump(JumpPointer::new(-2)), span),
+        ];
+        let code = decode(&encode(&loop_over_int)).unwrap();
+        assert_eq!(run(&code), Err(RuntimeErrorKind::WrongType {
+            op: "IterStart".to_string(),
+            found: vec![Value::Int(3)],
+        }));
+    }
+}
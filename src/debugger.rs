@@ -0,0 +1,168 @@
+use ae_skarp::eval::Inspector;
+use ae_skarp::utils::*;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
+use std::process;
+
+const HELP: &str = "\
+step, s        run the next instruction (an empty line does the same)
+continue, c    run until the next breakpoint
+break N        stop at the first instruction on line N
+break @N       stop at instruction number N
+delete N, @N   remove a breakpoint
+print NAME     show a variable
+vars           show every variable
+list           show the instructions around the current one
+quit, q        stop the program";
+
+/// Step debugger for `--debug`. It stops before the first instruction and then at every step or
+/// breakpoint, and reads commands from stdin.
+pub struct Debugger {
+    stepping: bool,
+    line_breaks: HashSet<usize>,
+    node_breaks: HashSet<usize>,
+    last_line: Option<usize>,
+    /// Every variable in the code, found from the instructions that use it.
+    variables: Vec<(String, Slot)>,
+}
+
+impl Debugger {
+    pub fn new(code: &[Instr]) -> Debugger {
+        let mut variables: Vec<(String, Slot)> = vec![];
+        for instr in code {
+            let (Node::Load(name, slot)
+            | Node::LoadIndex(name, slot)
+            | Node::LoadLen(name, slot)
+            | Node::Append(name, slot)
+            | Node::Store(name, slot)
+            | Node::SetIndex(name, slot)
+            | Node::IterNext(name, slot, _)) = &instr.node
+            else {
+                continue;
+            };
+            if !variables.iter().any(|(n, s)| n == name && s == slot) {
+                variables.push((name.clone(), *slot));
+            }
+        }
+        Debugger {
+            stepping: true,
+            line_breaks: HashSet::new(),
+            node_breaks: HashSet::new(),
+            last_line: None,
+            variables,
+        }
+    }
+
+    fn show(&self, rt: &Runtime, code: &[Instr]) {
+        let instr = &code[rt.op_counter];
+        println!("[{}] {} {:?}", rt.op_counter, instr.span, instr.node);
+        println!("stack: {}", repr_list(&rt.stack));
+        println!("mem: {}", repr_list(&rt.mem));
+    }
+
+    /// Reads commands until one of them runs the program again.
+    fn prompt(&mut self, rt: &Runtime, code: &[Instr]) -> Result<()> {
+        let stdin = io::stdin();
+        loop {
+            print!("(dbg) ");
+            io::stdout().flush()?;
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                // nothing more to read, so let the program finish
+                self.stepping = false;
+                self.line_breaks.clear();
+                self.node_breaks.clear();
+                println!();
+                return Ok(());
+            }
+
+            let mut words = line.split_whitespace();
+            let command = words.next().unwrap_or("step");
+            let arg = words.next();
+            match (command, arg) {
+                ("step" | "s", _) => {
+                    self.stepping = true;
+                    return Ok(());
+                }
+                ("continue" | "c", _) => {
+                    self.stepping = false;
+                    return Ok(());
+                }
+                ("break" | "b", Some(arg)) => match breakpoint(arg) {
+                    Some(Breakpoint::Line(line)) => drop(self.line_breaks.insert(line)),
+                    Some(Breakpoint::Node(i)) => drop(self.node_breaks.insert(i)),
+                    None => println!("expected a line number or @ and an instruction number, found '{}'", arg),
+                },
+                ("delete" | "d", Some(arg)) => match breakpoint(arg) {
+                    Some(Breakpoint::Line(line)) => drop(self.line_breaks.remove(&line)),
+                    Some(Breakpoint::Node(i)) => drop(self.node_breaks.remove(&i)),
+                    None => println!("expected a line number or @ and an instruction number, found '{}'", arg),
+                },
+                ("print" | "p", Some(name)) => {
+                    let mut found = false;
+                    for (n, slot) in self.variables.iter().filter(|(n, _)| n == name) {
+                        found = true;
+                        println!("{}@{} = {}", n, slot, variable(rt, *slot));
+                    }
+                    if !found {
+                        println!("no variable named '{}'", name);
+                    }
+                }
+                ("vars", _) => {
+                    for (name, slot) in &self.variables {
+                        println!("{}@{} = {}", name, slot, variable(rt, *slot));
+                    }
+                }
+                ("list" | "l", _) => {
+                    let start = rt.op_counter.saturating_sub(5);
+                    for (i, instr) in code.iter().enumerate().skip(start).take(11) {
+                        let marker = if i == rt.op_counter { "->" } else { "  " };
+                        println!("{} {}: {:?} ({})", marker, i, instr.node, instr.span);
+                    }
+                }
+                ("help" | "h", _) => println!("{}", HELP),
+                ("quit" | "q", _) => process::exit(0),
+                _ => println!("unknown command '{}', help lists the commands", line.trim()),
+            }
+        }
+    }
+}
+
+impl Inspector for Debugger {
+    fn before(&mut self, rt: &Runtime, code: &[Instr]) -> Result<()> {
+        let line = code[rt.op_counter].span.line;
+        // a line breakpoint stops once when the line is reached, not at every instruction on it
+        let new_line = self.last_line != Some(line);
+        self.last_line = Some(line);
+        let hit = self.node_breaks.contains(&rt.op_counter) || (new_line && self.line_breaks.contains(&line));
+        if !self.stepping && !hit {
+            return Ok(());
+        }
+        self.show(rt, code);
+        self.prompt(rt, code)
+    }
+}
+
+enum Breakpoint {
+    Line(usize),
+    Node(usize),
+}
+
+fn breakpoint(arg: &str) -> Option<Breakpoint> {
+    match arg.strip_prefix('@') {
+        Some(i) => i.parse().ok().map(Breakpoint::Node),
+        None => arg.parse().ok().map(Breakpoint::Line),
+    }
+}
+
+fn variable(rt: &Runtime, slot: Slot) -> String {
+    let value = match slot {
+        Slot::Global(i) => rt.mem.get(i),
+        Slot::Local(i) => rt.frames.last().and_then(|frame| frame.get(i)),
+    };
+    match value {
+        Some(Value::Null) | None => "(not set)".to_string(),
+        Some(value) => value.repr(),
+    }
+}
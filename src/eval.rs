@@ -1,62 +1,733 @@
-use std::collections::HashMap;
-
 use crate::utils::*;
-use anyhow::{anyhow, Result};
+use anyhow::Result;
+use std::io::Write;
+
+/// Something that looks at the program while it runs, like the debugger or `--trace`.
+pub trait Inspector {
+    /// Called before every instruction, with `rt.op_counter` pointing at it in `code`.
+    fn before(&mut self, _rt: &Runtime, _code: &[Instr]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after the instruction at `index` in `code` has run.
+    fn after(&mut self, _rt: &Runtime, _code: &[Instr], _index: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called instead of `after` when the instruction at `index` fails with `err`. The run stops
+    /// with `err` afterwards.
+    fn failed(&mut self, _rt: &Runtime, _code: &[Instr], _index: usize, _err: &anyhow::Error) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Running without an inspector compiles to the same loop as if there was no hook at all.
+impl Inspector for () {}
+
+/// Runs `code` from `rt.op_counter` until it reaches the end. The REPL keeps one `Runtime` and adds
+/// more code to the end for every input.
+pub fn run(rt: &mut Runtime, code: &[Instr]) -> Result<()> {
+    run_with(rt, code, &mut ())
+}
+
+pub fn run_with(rt: &mut Runtime, code: &[Instr], inspector: &mut impl Inspector) -> Result<()> {
+    let mut executed = 0;
+    while let Some(instr) = code.get(rt.op_counter) {
+        let index = rt.op_counter;
+        inspector.before(rt, code)?;
+        if let Err(err) = step(rt, instr, &mut executed) {
+            inspector.failed(rt, code, index, &err)?;
+            return Err(err);
+        }
+        inspector.after(rt, code, index)?;
+    }
+    Ok(())
+}
+
+/// Runs one instruction and moves `rt.op_counter` to the next one.
+fn step(rt: &mut Runtime, instr: &Instr, executed: &mut u64) -> Result<()> {
+    let Instr { node, span } = instr;
+    if let Some(max) = rt.limits.instructions.filter(|max| *executed >= *max) {
+        return Err(RuntimeError::at(*span, RuntimeErrorKind::InstructionLimit(max)));
+    }
+    *executed += 1;
+
+    match node {
+        Node::PushInt(x) => rt.stack.push(Value::Int(*x)),
+        Node::PushFloat(x) => rt.stack.push(Value::Float(*x)),
+        Node::PushBool(x) => rt.stack.push(Value::Bool(*x)),
+        Node::PushStr(x) => {
+            rt.stack.push(Value::Str(x.clone()));
+            allocated(rt, x.len());
+        }
+        Node::Operator(op) => {
+            let arity = op.arity();
+            if rt.stack.len() < arity {
+                return Err(RuntimeError::at(*span, RuntimeErrorKind::StackUnderflow {
+                    op: format!("{:?}", op),
+                    needed: arity,
+                    found: rt.stack.len(),
+                }));
+            }
+            operate(rt, *op).map_err(|kind| RuntimeError::at(*span, kind))?;
+        }
+        Node::Load(name, slot) => {
+            let Some(value) = load(rt, *slot) else {
+                return Err(RuntimeError::at(*span, RuntimeErrorKind::UnknownIdentifier(name.clone())));
+            };
+            let value = value.clone();
+            allocated(rt, value.heap_size());
+            rt.stack.push(value);
+        },
+        Node::Jump(x) => rt.op_counter = x.resolve(rt.op_counter),
+        Node::JumpIfFalse(x) => {
+            let condition_resualt = match rt.stack.pop() {
+                Some(Value::Bool(b)) => b,
+                found => return Err(RuntimeError::at(*span, RuntimeErrorKind::ExpectedBool { found })),
+            };
+
+            if !condition_resualt {
+                rt.op_counter = x.resolve(rt.op_counter)
+            }
+        }
+        Node::EndOfIf => (),
+        Node::Store(name, slot) => {
+            let value = pop(rt, node, *span)?;
+            store(rt, name, *slot, value).map_err(|kind| RuntimeError::at(*span, kind))?;
+        },
+        Node::Clear(name, slot) => {
+            store(rt, name, *slot, Value::Null).map_err(|kind| RuntimeError::at(*span, kind))?;
+        }
+        Node::Function(name, x) => {
+            rt.functions.insert(name.to_string(), rt.op_counter);
+            rt.op_counter = x.resolve(rt.op_counter);
+        }
+        Node::Call(name) => {
+            let Some(address) = rt.functions.get(name) else {
+                return Err(RuntimeError::at(*span, RuntimeErrorKind::UnknownFunction(name.clone())));
+            };
+            if let Some(max) = rt.limits.call_depth.filter(|max| rt.return_stack.len() >= *max) {
+                return Err(RuntimeError::at(*span, RuntimeErrorKind::CallDepthLimit(max)));
+            }
+            rt.return_stack.push(rt.op_counter);
+            rt.frames.push(vec![]);
+            rt.op_counter = *address;
+        }
+        Node::CallHost(name) => call_host(rt, name).map_err(|kind| RuntimeError::at(*span, kind))?,
+        Node::FuncReturn => {
+            let Some(address) = rt.return_stack.pop() else {
+                return Err(RuntimeError::at(*span, RuntimeErrorKind::ReturnOutsideFunction));
+            };
+            if let Some(frame) = rt.frames.pop() {
+                freed(rt, frame.iter().map(Value::heap_size).sum());
+            }
+            rt.op_counter = address;
+        }
+        Node::MakeList(n) => {
+            if rt.stack.len() < *n {
+                return Err(RuntimeError::at(*span, RuntimeErrorKind::StackUnderflow {
+                    op: format!("{:?}", node),
+                    needed: *n,
+                    found: rt.stack.len(),
+                }));
+            }
+            let values = rt.stack.split_off(rt.stack.len() - n);
+            rt.stack.push(Value::List(values));
+            allocated(rt, n * std::mem::size_of::<Value>());
+        }
+        Node::RepeatList(n) => {
+            let value = pop(rt, node, *span)?;
+            let heap_size = value.heap_size();
+            let size = n.checked_mul(std::mem::size_of::<Value>() + heap_size);
+            reserve(rt, size).map_err(|kind| RuntimeError::at(*span, kind))?;
+            let (Some(size), Some(values)) = (size, repeat(value, *n)) else {
+                return Err(RuntimeError::at(*span, RuntimeErrorKind::ListTooLong(*n)));
+            };
+            rt.stack.push(Value::List(values));
+            freed(rt, heap_size);
+            allocated(rt, size);
+        }
+        Node::Index => {
+            let index = pop(rt, node, *span)?;
+            let container = pop(rt, node, *span)?;
+            let value = element(node, &container, index).map_err(|kind| RuntimeError::at(*span, kind))?;
+            freed(rt, container.heap_size());
+            allocated(rt, value.heap_size());
+            rt.stack.push(value);
+        }
+        Node::LoadIndex(name, slot) => {
+            let index = pop(rt, node, *span)?;
+            let Some(container) = load(rt, *slot) else {
+                return Err(RuntimeError::at(*span, RuntimeErrorKind::UnknownIdentifier(name.clone())));
+            };
+            let value = element(node, container, index).map_err(|kind| RuntimeError::at(*span, kind))?;
+            allocated(rt, value.heap_size());
+            rt.stack.push(value);
+        }
+        Node::LoadLen(name, slot) => {
+            let len = match load(rt, *slot) {
+                Some(Value::List(values)) => values.len(),
+                Some(Value::Str(s)) => s.chars().count(),
+                Some(other) => return Err(RuntimeError::at(*span, wrong_type(node, vec![other.clone()]))),
+                None => {
+                    return Err(RuntimeError::at(*span, RuntimeErrorKind::UnknownIdentifier(name.clone())));
+                }
+            };
+            rt.stack.push(Value::Int(len as i64));
+        }
+        Node::Append(name, slot) => {
+            let value = pop(rt, node, *span)?;
+            match load(rt, *slot) {
+                Some(Value::List(values)) => {
+                    values.push(value);
+                    allocated(rt, std::mem::size_of::<Value>());
+                }
+                Some(other) => return Err(RuntimeError::at(*span, wrong_type(node, vec![other.clone()]))),
+                None => {
+                    return Err(RuntimeError::at(*span, RuntimeErrorKind::UnknownIdentifier(name.clone())));
+                }
+            }
+        }
+        Node::SetIndex(name, slot) => {
+            let value = pop(rt, node, *span)?;
+            let index = pop(rt, node, *span)?;
+            let Value::Int(i) = index else {
+                return Err(RuntimeError::at(*span, wrong_type(node, vec![index])));
+            };
+            let values = match load(rt, *slot) {
+                Some(Value::List(values)) => values,
+                Some(other) => return Err(RuntimeError::at(*span, wrong_type(node, vec![other.clone()]))),
+                None => {
+                    return Err(RuntimeError::at(*span, RuntimeErrorKind::UnknownIdentifier(name.clone())));
+                }
+            };
+            let len = values.len();
+            if i < 0 || i as usize >= len {
+                return Err(RuntimeError::at(*span, RuntimeErrorKind::IndexOutOfBounds { index: i, len }));
+            }
+            let old = std::mem::replace(&mut values[i as usize], value);
+            freed(rt, old.heap_size());
+        }
+        Node::PopList => {
+            let mut values = match pop(rt, node, *span)? {
+                Value::List(values) => values,
+                other => return Err(RuntimeError::at(*span, wrong_type(node, vec![other]))),
+            };
+            let Some(value) = values.pop() else {
+                return Err(RuntimeError::at(*span, RuntimeErrorKind::PopFromEmptyList));
+            };
+            rt.stack.push(Value::List(values));
+            rt.stack.push(value);
+            freed(rt, std::mem::size_of::<Value>());
+        }
+        Node::IterStart => {
+            match rt.stack.last() {
+                Some(Value::Str(s)) => {
+                    // the loop goes over a list with a string for every character, which takes
+                    // one `Value` more for every character than the string itself
+                    let size = s.chars().count().checked_mul(std::mem::size_of::<Value>());
+                    reserve(rt, size).map_err(|kind| RuntimeError::at(*span, kind))?;
+                    allocated(rt, size.unwrap_or_default());
+                }
+                Some(Value::Range(..) | Value::List(_)) | None => {}
+                Some(other) => return Err(RuntimeError::at(*span, wrong_type(node, vec![other.clone()]))),
+            }
+            let iterable = pop(rt, node, *span)?;
+            rt.iterators.push(LoopIter::new(iterable));
+        }
+        Node::IterNext(name, slot, x) => {
+            let next = rt.iterators.last_mut().and_then(|iter| iter.next());
+            if let Some(value) = next {
+                allocated(rt, value.heap_size());
+                store(rt, name, *slot, value).map_err(|kind| RuntimeError::at(*span, kind))?;
+            } else {
+                if let Some(iter) = rt.iterators.pop() {
+                    freed(rt, iter.heap_size());
+                }
+                rt.op_counter = x.resolve(rt.op_counter);
+            }
+        }
+    }
+    check_limits(rt).map_err(|kind| RuntimeError::at(*span, kind))?;
+    rt.op_counter = rt.op_counter.wrapping_add(1);
+    Ok(())
+}
 
-pub fn eval(ast: Vec<Node>) -> Result<u8> {
-    let mut rt = Runtime {
-        stack: vec![],
-        mem: HashMap::new(),
-        op_counter: 0,
+/// Checks the stack and memory limits after an instruction has run.
+fn check_limits(rt: &Runtime) -> Result<(), RuntimeErrorKind> {
+    if let Some(max) = rt.limits.stack_depth.filter(|max| rt.stack.len() > *max) {
+        return Err(RuntimeErrorKind::StackLimit(max));
+    }
+    if let Some(max) = rt.limits.memory.filter(|max| rt.memory > *max) {
+        return Err(RuntimeErrorKind::MemoryLimit(max));
+    }
+    Ok(())
+}
+
+/// Checks that `extra` more bytes of strings and lists fit within the memory limit before they are
+/// made, so a huge list fails instead of taking all the memory first. `None` is more bytes than a
+/// `usize` can count.
+fn reserve(rt: &Runtime, extra: Option<usize>) -> Result<(), RuntimeErrorKind> {
+    let Some(max) = rt.limits.memory else {
+        return Ok(());
     };
+    match extra.and_then(|extra| rt.memory.checked_add(extra)) {
+        Some(used) if used <= max => Ok(()),
+        _ => Err(RuntimeErrorKind::MemoryLimit(max)),
+    }
+}
+
+/// Counts `bytes` of new strings and lists in `rt.memory`.
+fn allocated(rt: &mut Runtime, bytes: usize) {
+    rt.memory = rt.memory.saturating_add(bytes);
+}
+
+/// Stops counting `bytes` of strings and lists that were dropped.
+fn freed(rt: &mut Runtime, bytes: usize) {
+    rt.memory = rt.memory.saturating_sub(bytes);
+}
+
+/// Runs `op` on the values on top of the stack, which `run_with` has checked are there. Results
+/// replace the values in place where they can, so nothing is allocated. The parser has checked the
+/// types, but code from a bytecode file has not been checked, so values of the wrong type fail with
+/// `WrongType` and are left on the stack.
+fn operate(rt: &mut Runtime, op: Op) -> Result<(), RuntimeErrorKind> {
+    let stack = &mut rt.stack;
+    match op {
+        Op::DivInt | Op::ModInt if matches!(operands(stack, op), [Value::Int(_), Value::Int(0)]) => {
+            return Err(RuntimeErrorKind::DivisionByZero);
+        }
+        Op::SumInt => ints(stack, op, |a, b| a.checked_add(b).map(Value::Int))?,
+        Op::SubInt => ints(stack, op, |a, b| a.checked_sub(b).map(Value::Int))?,
+        Op::MultInt => ints(stack, op, |a, b| a.checked_mul(b).map(Value::Int))?,
+        Op::DivInt => ints(stack, op, |a, b| a.checked_div(b).map(Value::Int))?,
+        Op::ModInt => ints(stack, op, |a, b| a.checked_rem(b).map(Value::Int))?,
+
+        Op::SumFloat => floats(stack, op, |a, b| Value::Float(a + b))?,
+        Op::SubFloat => floats(stack, op, |a, b| Value::Float(a - b))?,
+        Op::MultFloat => floats(stack, op, |a, b| Value::Float(a * b))?,
+        Op::DivFloat => floats(stack, op, |a, b| Value::Float(a / b))?,
+        Op::ModFloat => floats(stack, op, |a, b| Value::Float(a % b))?,
+
+        Op::ConcatStr => match operands(stack, op) {
+            [Value::Str(a), Value::Str(b)] => {
+                a.push_str(b);
+                stack.pop();
+            }
+            _ => return Err(wrong_operands(stack, op)),
+        },
+
+        Op::EqInt => ints(stack, op, |a, b| Some(Value::Bool(a == b)))?,
+        Op::LtInt => ints(stack, op, |a, b| Some(Value::Bool(a < b)))?,
+        Op::GtInt => ints(stack, op, |a, b| Some(Value::Bool(a > b)))?,
+        Op::LqInt => ints(stack, op, |a, b| Some(Value::Bool(a <= b)))?,
+        Op::GqInt => ints(stack, op, |a, b| Some(Value::Bool(a >= b)))?,
+        Op::EqFloat => floats(stack, op, |a, b| Value::Bool(a == b))?,
+        Op::LtFloat => floats(stack, op, |a, b| Value::Bool(a < b))?,
+        Op::GtFloat => floats(stack, op, |a, b| Value::Bool(a > b))?,
+        Op::LqFloat => floats(stack, op, |a, b| Value::Bool(a <= b))?,
+        Op::GqFloat => floats(stack, op, |a, b| Value::Bool(a >= b))?,
+        Op::AndBool => match operands(stack, op) {
+            [Value::Bool(a), Value::Bool(b)] => {
+                *a = *a && *b;
+                stack.pop();
+            }
+            _ => return Err(wrong_operands(stack, op)),
+        },
+
+        Op::IntToFloat => convert(stack, op, |value| match value {
+            Value::Int(a) => Some(Value::Float(*a as f64)),
+            _ => None,
+        })?,
+        Op::FloatToInt => convert(stack, op, |value| match value {
+            Value::Float(a) => Some(Value::Int(*a as i64)),
+            _ => None,
+        })?,
+
+        Op::ListLen => {
+            let bytes = stack[stack.len() - 1].heap_size();
+            convert(stack, op, |value| match value {
+                Value::List(a) => Some(Value::Int(a.len() as i64)),
+                _ => None,
+            })?;
+            freed(rt, bytes);
+        }
+        Op::StrLen => {
+            let bytes = stack[stack.len() - 1].heap_size();
+            convert(stack, op, |value| match value {
+                Value::Str(a) => Some(Value::Int(a.chars().count() as i64)),
+                _ => None,
+            })?;
+            freed(rt, bytes);
+        }
+        Op::ListPush => match operands(stack, op) {
+            [Value::List(a), b] => {
+                a.push(std::mem::replace(b, Value::Null));
+                stack.pop();
+                allocated(rt, std::mem::size_of::<Value>());
+            }
+            _ => return Err(wrong_operands(stack, op)),
+        },
+
+        Op::RangeExclusive => ints(stack, op, |a, b| Some(Value::Range(a, b)))?,
+        Op::RangeInclusive => ints(stack, op, |a, b| Some(Value::Range(a, b.checked_add(1)?)))?,
+
+        Op::Dup => {
+            let top = stack[stack.len() - 1].clone();
+            let bytes = top.heap_size();
+            stack.push(top);
+            allocated(rt, bytes);
+        }
+        Op::Drop => {
+            if let Some(value) = stack.pop() {
+                freed(rt, value.heap_size());
+            }
+        }
+        Op::Swap => {
+            let len = stack.len();
+            stack.swap(len - 1, len - 2);
+        }
+        Op::Over => {
+            let under = stack[stack.len() - 2].clone();
+            let bytes = under.heap_size();
+            stack.push(under);
+            allocated(rt, bytes);
+        }
+        Op::Println => {
+            if let Some(value) = stack.pop() {
+                writeln!(rt.output, "{}", value).map_err(|err| RuntimeErrorKind::Output(err.to_string()))?;
+                freed(rt, value.heap_size());
+            }
+        }
 
-    while let Some(node) = ast.get(rt.op_counter) {
-        // println!("Stack: {:?}\nNode: {:?}", rt.stack, node);
-
-        match node {
-            Node::PushInt(x) => rt.stack.push(Value::Int(*x)),
-            Node::PushBool(x) => rt.stack.push(Value::Bool(*x)),
-            Node::PushStr(x) => rt.stack.push(Value::Str(x.clone())),
-            Node::Operator { op: _, arity, func } => {
-                let mut args = vec![];
-                for _ in 1..=*arity {
-                    args.push(rt.stack.pop().expect("should not happen"))
-                }
-                args.reverse();
-                let opt_res = func(&args);
-                match opt_res {
-                    Some(mut res) => rt.stack.append(&mut res),
-                    None => (),
-                }
-            }
-            Node::Identifier(name) => {
-                let optional_value = rt.mem.get(name);
-                if let Some(value) = optional_value {
-                    rt.stack.push(value.clone());
-                } else {
-                    return Err(anyhow!("could not find identifier '{}'", name));
-                }
-            },
-            Node::Jump(x) => rt.op_counter = x.resolve(rt.op_counter),
-            Node::JumpIfFalse(x) => {
-                let Some(Value::Bool(condition_resualt)) = rt.stack.pop() else {
-                    return Err(anyhow!("have not yet made typechecking for loops"))
-                };
-
-                if !condition_resualt {
-                    rt.op_counter = x.resolve(rt.op_counter)
-                }
-            }
-            Node::EndOfIf => (),
-            Node::DefineConst(name) => {
-                rt.mem.insert(name.to_string(), Value::Null);
-            },
-            Node::Return(name) => {
-                let value = rt.stack.pop().unwrap();
-                rt.mem.insert(name.to_string(), value);
-            },
-        }
-        rt.op_counter += 1;
-    }
-    Ok(0u8)
+        Op::Assert => match operands(stack, op) {
+            [Value::Bool(b)] => {
+                let b = *b;
+                stack.pop();
+                if !b {
+                    return Err(RuntimeErrorKind::AssertionFailed);
+                }
+            }
+            _ => return Err(wrong_operands(stack, op)),
+        },
+        Op::AssertEq => {
+            let (Some(right), Some(left)) = (stack.pop(), stack.pop()) else {
+                return Ok(());
+            };
+            if left != right {
+                return Err(RuntimeErrorKind::NotEqual { left, right });
+            }
+            freed(rt, left.heap_size() + right.heap_size());
+        }
+    }
+    Ok(())
+}
+
+/// Calls a Rust function with the values its signature asks for, and checks that it gives back what
+/// the signature promised, since the parser has already trusted it.
+fn call_host(rt: &mut Runtime, name: &str) -> Result<(), RuntimeErrorKind> {
+    let Some(host) = rt.host_functions.get_mut(name) else {
+        return Err(RuntimeErrorKind::UnknownFunction(name.to_string()));
+    };
+    let inputs = &host.signature.inputs;
+    if rt.stack.len() < inputs.len() {
+        return Err(RuntimeErrorKind::StackUnderflow {
+            op: name.to_string(),
+            needed: inputs.len(),
+            found: rt.stack.len(),
+        });
+    }
+    let args = rt.stack.split_off(rt.stack.len() - inputs.len());
+    let bytes = args.iter().map(Value::heap_size).sum();
+    let error = |message| RuntimeErrorKind::HostFunction { name: name.to_string(), message };
+    if !args.iter().zip(inputs).all(|(value, t)| value.is(t)) {
+        return Err(error(format!("expected {} but got {}", type_list(inputs), repr_list(&args))));
+    }
+
+    let results = (host.func)(args).map_err(|err| error(err.to_string()))?;
+    let outputs = &host.signature.outputs;
+    if results.len() != outputs.len() || !results.iter().zip(outputs).all(|(value, t)| value.is(t)) {
+        return Err(error(format!("should return {} but returned {}", type_list(outputs), repr_list(&results))));
+    }
+    freed(rt, bytes);
+    allocated(rt, results.iter().map(Value::heap_size).sum());
+    rt.stack.extend(results);
+    Ok(())
+}
+
+/// The values `op` takes off the stack, which `run_with` has checked are there.
+fn operands(stack: &mut [Value], op: Op) -> &mut [Value] {
+    let len = stack.len();
+    &mut stack[len - op.arity()..]
+}
+
+fn wrong_operands(stack: &[Value], op: Op) -> RuntimeErrorKind {
+    wrong_type(&Node::Operator(op), stack[stack.len() - op.arity()..].to_vec())
+}
+
+/// Replaces the two integers on top of the stack with `func` of them, and fails with
+/// `IntegerOverflow` when `func` returns `None`.
+fn ints(stack: &mut Vec<Value>, op: Op, func: impl FnOnce(i64, i64) -> Option<Value>) -> Result<(), RuntimeErrorKind> {
+    let &mut [Value::Int(a), Value::Int(b)] = operands(stack, op) else {
+        return Err(wrong_operands(stack, op));
+    };
+    let value = func(a, b).ok_or(RuntimeErrorKind::IntegerOverflow)?;
+    stack.truncate(stack.len() - 2);
+    stack.push(value);
+    Ok(())
+}
+
+/// Replaces the two floats on top of the stack with `func` of them.
+fn floats(stack: &mut Vec<Value>, op: Op, func: impl FnOnce(f64, f64) -> Value) -> Result<(), RuntimeErrorKind> {
+    let &mut [Value::Float(a), Value::Float(b)] = operands(stack, op) else {
+        return Err(wrong_operands(stack, op));
+    };
+    stack.truncate(stack.len() - 2);
+    stack.push(func(a, b));
+    Ok(())
+}
+
+/// Replaces the value on top of the stack with `func` of it, where `None` means `func` does not
+/// take values of its type.
+fn convert(stack: &mut [Value], op: Op, func: impl FnOnce(&Value) -> Option<Value>) -> Result<(), RuntimeErrorKind> {
+    let top = stack.len() - 1;
+    let Some(value) = func(&stack[top]) else {
+        return Err(wrong_operands(stack, op));
+    };
+    stack[top] = value;
+    Ok(())
+}
+
+fn load(rt: &mut Runtime, slot: Slot) -> Option<&mut Value> {
+    let value = match slot {
+        Slot::Global(i) => rt.mem.get_mut(i),
+        Slot::Local(i) => rt.frames.last_mut().and_then(|frame| frame.get_mut(i)),
+    };
+    value.filter(|value| **value != Value::Null)
+}
+
+/// Stores `value` in the variable `name`. Only a local slot outside of a function can fail, which
+/// the parser never makes but a bytecode file can have.
+fn store(rt: &mut Runtime, name: &str, slot: Slot, value: Value) -> Result<(), RuntimeErrorKind> {
+    let (memory, i) = match slot {
+        Slot::Global(i) => (&mut rt.mem, i),
+        Slot::Local(i) => match rt.frames.last_mut() {
+            Some(frame) => (frame, i),
+            None => return Err(RuntimeErrorKind::LocalOutsideFunction(name.to_string())),
+        },
+    };
+    if memory.len() <= i {
+        memory.resize(i + 1, Value::Null);
+    }
+    let old = std::mem::replace(&mut memory[i], value);
+    freed(rt, old.heap_size());
+    Ok(())
+}
+
+/// A list of `n` copies of `value`, or `None` if there is no room for the list.
+fn repeat(value: Value, n: usize) -> Option<Vec<Value>> {
+    let mut values = vec![];
+    values.try_reserve_exact(n).ok()?;
+    values.resize(n, value);
+    Some(values)
+}
+
+/// The element at `index` in a list or string.
+fn element(node: &Node, container: &Value, index: Value) -> Result<Value, RuntimeErrorKind> {
+    match (container, index) {
+        (Value::List(values), Value::Int(i)) => {
+            let len = values.len();
+            if i < 0 || i as usize >= len {
+                return Err(RuntimeErrorKind::IndexOutOfBounds { index: i, len });
+            }
+            Ok(values[i as usize].clone())
+        }
+        (Value::Str(s), Value::Int(i)) => {
+            let c = if i < 0 { None } else { s.chars().nth(i as usize) };
+            let Some(c) = c else {
+                let len = s.chars().count();
+                return Err(RuntimeErrorKind::IndexOutOfBounds { index: i, len });
+            };
+            Ok(Value::Str(c.to_string()))
+        }
+        (container, index) => Err(wrong_type(node, vec![container.clone(), index])),
+    }
+}
+
+/// The error for values of the wrong type, which the parser never lets through but code read from
+/// a bytecode file has not been checked for.
+fn wrong_type(node: &Node, found: Vec<Value>) -> RuntimeErrorKind {
+    RuntimeErrorKind::WrongType { op: format!("{:?}", node), found }
+}
+
+fn pop(rt: &mut Runtime, node: &Node, span: Span) -> Result<Value> {
+    rt.stack.pop().ok_or_else(|| {
+        RuntimeError::at(span, RuntimeErrorKind::StackUnderflow {
+            op: format!("{:?}", node),
+            needed: 1,
+            found: 0,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lower, parser::Parser, Interpreter};
+
+    /// Runs `source` with `limits` and returns the error it stops with, and where.
+    fn error(source: &str, limits: Limits) -> (RuntimeErrorKind, Span) {
+        let mut interpreter = Interpreter::new();
+        interpreter.capture_output();
+        interpreter.set_limits(limits);
+        let err = interpreter.eval(source).unwrap_err();
+        let err = err.downcast::<RuntimeError>().unwrap();
+        (err.kind, err.span)
+    }
+
+    #[test]
+    fn list_too_long() {
+        let (kind, span) = error("[0; 1000000000000000000] lengde skrivnl", Limits::default());
+        assert_eq!(kind, RuntimeErrorKind::ListTooLong(1000000000000000000));
+        assert_eq!(span, Span::new(1, 1));
+
+        let (kind, span) = error("var xs: [Htall; 100000000000000000]", Limits::default());
+        assert_eq!(kind, RuntimeErrorKind::ListTooLong(100000000000000000));
+        assert_eq!(span, Span::new(1, 9));
+    }
+
+    #[test]
+    fn stack_limit() {
+        let limits = Limits { stack_depth: Some(3), ..Limits::default() };
+        assert_eq!(error("1 2 3 + 4 5", limits), (RuntimeErrorKind::StackLimit(3), Span::new(1, 11)));
+    }
+
+    #[test]
+    fn memory_limit() {
+        // the string `skrivnl` wrote is gone, so only the last two count
+        let limits = Limits { memory: Some(4), ..Limits::default() };
+        let source = "\"abc\" skrivnl \"de\" \"fgh\"";
+        assert_eq!(error(source, limits), (RuntimeErrorKind::MemoryLimit(4), Span::new(1, 20)));
+        // the list is never made, even though it would fit in a usize
+        let limits = Limits { memory: Some(1000), ..Limits::default() };
+        assert_eq!(error("[0; 1000000000000]", limits), (RuntimeErrorKind::MemoryLimit(1000), Span::new(1, 1)));
+        // looping over a string makes a list of its characters before the first round
+        let limits = Limits { memory: Some(100), ..Limits::default() };
+        let source = "for hver c i \"abcdefgh\" { }";
+        assert_eq!(error(source, limits), (RuntimeErrorKind::MemoryLimit(100), Span::new(1, 1)));
+    }
+
+    #[test]
+    fn call_depth_limit() {
+        let limits = Limits { call_depth: Some(10), ..Limits::default() };
+        let source = "funk evig { evig }\nevig";
+        assert_eq!(error(source, limits), (RuntimeErrorKind::CallDepthLimit(10), Span::new(1, 13)));
+    }
+
+    #[test]
+    fn integer_overflow() {
+        let max = i64::MAX;
+        let limits = Limits::default();
+        assert_eq!(error(&format!("{} 1 +", max), limits), (RuntimeErrorKind::IntegerOverflow, Span::new(1, 23)));
+        assert_eq!(error(&format!("{} 2 *", max), limits), (RuntimeErrorKind::IntegerOverflow, Span::new(1, 23)));
+        let source = format!("for hver i i 0..={} {{ }}", max);
+        assert_eq!(error(&source, limits), (RuntimeErrorKind::IntegerOverflow, Span::new(1, 15)));
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let limits = Limits::default();
+        assert_eq!(error("1 0 /", limits), (RuntimeErrorKind::DivisionByZero, Span::new(1, 5)));
+        assert_eq!(error("var x = 0\n7 x %", limits), (RuntimeErrorKind::DivisionByZero, Span::new(2, 5)));
+    }
+
+    /// Checks that `rt.memory` is the same as counting everything again after every instruction.
+    struct MemoryCheck;
+
+    impl Inspector for MemoryCheck {
+        fn after(&mut self, rt: &Runtime, code: &[Instr], index: usize) -> Result<()> {
+            assert_eq!(rt.memory, rt.count_memory(), "after {:?} at {}", code[index], index);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn memory_is_counted_as_it_changes() {
+        let source = r#"
+            funk hils Streng -> Streng { "hei " snu + }
+            funk tell Liste<Streng> -> Htall {
+                var ys = ["y"; 3]
+                slipp ys lengde
+            }
+            var xs = ["a", "bc"]
+            xs = xs "def" legg_til
+            xs[0] = "g"
+            xs[1] skrivnl
+            xs lengde skrivnl
+            xs dup over slipp slipp lengde slipp
+            [["x"; 2]; 3] ta_ut snu slipp lengde skrivnl
+            for hver c i "æøå" { c hils skrivnl }
+            for hver x i xs { x x + skrivnl }
+            xs gitt a: Liste<Streng> { a[0] "g" påstå_lik }
+            "ab" lengde 2 påstå_lik
+            xs tell ["p", "q"][1] skrivnl skrivnl
+        "#;
+        let mut rt = Runtime::with_output(Box::new(std::io::sink()));
+        let code = lower::lower(Parser::parse(source, None).unwrap().ast);
+        run_with(&mut rt, &code, &mut MemoryCheck).unwrap();
+        assert!(rt.memory > 0);
+    }
+
+    #[test]
+    fn values_are_cleared_when_their_scope_ends() {
+        let source = r#"
+            hvis sann {
+                var xs = ["abc"; 100]
+            }
+            for hver s i ["def"; 100] {
+                var t = s
+            }
+            "ghi" gitt u: Streng { }
+            funk f {
+                når usann {
+                    var v = "jkl"
+                }
+            }
+            f
+        "#;
+        let mut rt = Runtime::with_output(Box::new(std::io::sink()));
+        let code = lower::lower(Parser::parse(source, None).unwrap().ast);
+        run_with(&mut rt, &code, &mut MemoryCheck).unwrap();
+        assert!(rt.mem.iter().all(|value| *value == Value::Null), "{:?}", rt.mem);
+        assert_eq!(rt.memory, 0);
+    }
+
+    /// Remembers which instructions ran and which one failed.
+    #[derive(Default)]
+    struct Record {
+        ran: Vec<usize>,
+        failed: Option<(usize, String)>,
+    }
+
+    impl Inspector for Record {
+        fn after(&mut self, _rt: &Runtime, _code: &[Instr], index: usize) -> Result<()> {
+            self.ran.push(index);
+            Ok(())
+        }
+
+        fn failed(&mut self, _rt: &Runtime, _code: &[Instr], index: usize, err: &anyhow::Error) -> Result<()> {
+            self.failed = Some((index, err.to_string()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn inspector_sees_the_instruction_that_fails() {
+        let mut rt = Runtime::with_output(Box::new(std::io::sink()));
+        let code = lower::lower(Parser::parse("1 2 + 0 / skrivnl", None).unwrap().ast);
+        let mut record = Record::default();
+        assert!(run_with(&mut rt, &code, &mut record).is_err());
+        assert_eq!(record.ran, [0, 1, 2, 3]);
+        assert_eq!(record.failed, Some((4, "1:9: division by zero".to_string())));
+    }
 }
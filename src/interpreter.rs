@@ -0,0 +1,420 @@
+use crate::ast;
+use crate::bytecode;
+use crate::eval::{self, Inspector};
+use crate::lower;
+use crate::optimize;
+use crate::parser::{Parser, Snapshot};
+use crate::utils::*;
+use anyhow::{anyhow, Result};
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+/// Runs æ# code from Rust. The stack, variables and functions are kept from one program to the
+/// next, so code can be run a piece at a time, like in the REPL.
+///
+/// ```
+/// use ae_skarp::{Interpreter, Value};
+///
+/// let mut interpreter = Interpreter::new();
+/// interpreter.capture_output();
+/// interpreter.set_var("x", Value::Int(20)).unwrap();
+/// interpreter.eval("x 22 + dup skrivnl").unwrap();
+/// assert_eq!(interpreter.stack(), &[Value::Int(42)]);
+/// assert_eq!(interpreter.take_output(), "42\n");
+/// ```
+pub struct Interpreter {
+    parser: Parser,
+    runtime: Runtime,
+    /// The bodies of the functions defined so far, and the program that is running after them.
+    /// Functions are called by their position in it, so every program is added to the end instead
+    /// of replacing it, and only cut off once it has run.
+    code: Vec<Instr>,
+    optimize: bool,
+    captured: Option<Rc<RefCell<Vec<u8>>>>,
+    /// Counts the changes to what the parser knows, so a program parsed before one of them is not
+    /// run after it.
+    version: u64,
+}
+
+/// Parsed code, ready to be run by the `Interpreter` that parsed it.
+pub struct Program {
+    tree: String,
+    comments: Vec<Comment>,
+    unoptimized: Option<Vec<Instr>>,
+    code: Vec<Instr>,
+    /// What the parser knows once the program has run, and the `Interpreter::version` it was parsed
+    /// at. Unknown for bytecode.
+    after: Option<(Snapshot, u64)>,
+}
+
+impl Program {
+    /// Reads a program compiled with `to_bytecode`.
+    pub fn from_bytecode(bytes: &[u8]) -> Result<Program> {
+        Ok(Program {
+            tree: String::new(),
+            comments: vec![],
+            unoptimized: None,
+            code: bytecode::decode(bytes)?,
+            after: None,
+        })
+    }
+
+    pub fn to_bytecode(&self) -> Vec<u8> {
+        bytecode::encode(&self.code)
+    }
+
+    /// The tree the parser built, as indented text.
+    pub fn tree(&self) -> &str {
+        &self.tree
+    }
+
+    #[doc(hidden)]
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
+    /// The instructions that run.
+    #[doc(hidden)]
+    pub fn code(&self) -> &[Instr] {
+        &self.code
+    }
+
+    /// The instructions from before they were optimised, if they were.
+    #[doc(hidden)]
+    pub fn unoptimized(&self) -> Option<&[Instr]> {
+        self.unoptimized.as_deref()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter {
+            parser: Parser::parse("", None).expect("empty code always parses"),
+            runtime: Runtime::new(),
+            code: vec![],
+            optimize: true,
+            captured: None,
+            version: 0,
+        }
+    }
+
+    /// Turns the optimiser on or off for the programs parsed after this. It is on by default.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// Sets the limits for the programs run after this. Every run gets its own instruction budget.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.runtime.limits = limits;
+    }
+
+    /// Sends the output of `skrivnl` to `output` instead of stdout.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.runtime.output = output;
+        self.captured = None;
+    }
+
+    /// Keeps the output of `skrivnl` so it can be read with `take_output`.
+    pub fn capture_output(&mut self) {
+        let buffer = Rc::new(RefCell::new(vec![]));
+        self.runtime.output = Box::new(Captured(buffer.clone()));
+        self.captured = Some(buffer);
+    }
+
+    /// Returns the output captured since the last call. Empty unless `capture_output` was called.
+    pub fn take_output(&mut self) -> String {
+        let Some(buffer) = &self.captured else {
+            return String::new();
+        };
+        let bytes = std::mem::take(&mut *buffer.borrow_mut());
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Lets æ# code call `func` as `name`. The parser checks calls against `inputs` and `outputs`
+    /// like it does for a `funk`, and `func` gets the inputs with the deepest value first. State the
+    /// function needs, like a database connection, can be moved into the closure.
+    ///
+    /// ```
+    /// use ae_skarp::{Interpreter, Type, Value};
+    /// use std::collections::HashMap;
+    ///
+    /// let users = HashMap::from([(1, "Kari"), (2, "Ola")]);
+    /// let mut interpreter = Interpreter::new();
+    /// interpreter
+    ///     .register("bruker", &[Type::Int], &[Type::Str], move |args| match &args[..] {
+    ///         [Value::Int(id)] => match users.get(id) {
+    ///             Some(name) => Ok(vec![Value::Str(name.to_string())]),
+    ///             None => Err(anyhow::anyhow!("no user with id {}", id)),
+    ///         },
+    ///         _ => unreachable!("the parser checks the arguments"),
+    ///     })
+    ///     .unwrap();
+    /// interpreter.eval("2 bruker").unwrap();
+    /// assert_eq!(interpreter.stack(), &[Value::Str("Ola".to_string())]);
+    /// assert!(interpreter.eval("\"1\" bruker").is_err());
+    /// assert!(interpreter.eval("3 bruker").is_err());
+    /// ```
+    pub fn register(
+        &mut self,
+        name: &str,
+        inputs: &[Type],
+        outputs: &[Type],
+        func: impl FnMut(Vec<Value>) -> Result<Vec<Value>> + 'static,
+    ) -> Result<()> {
+        let signature = Signature {
+            inputs: inputs.to_vec(),
+            outputs: outputs.to_vec(),
+        };
+        self.parser.define_host_function(name, signature.clone())?;
+        self.version += 1;
+        self.runtime.host_functions.insert(
+            name.to_string(),
+            HostFunction {
+                signature,
+                func: Box::new(func),
+            },
+        );
+        Ok(())
+    }
+
+    /// Parses `source` as the code that comes after everything run before, so it can use the
+    /// variables, functions and stack from before. What the program declares is only known to the
+    /// programs parsed after it has run, and it can not be run once another program has.
+    ///
+    /// ```
+    /// use ae_skarp::Interpreter;
+    ///
+    /// let mut interpreter = Interpreter::new();
+    /// let first = interpreter.parse("var x = 1").unwrap();
+    /// assert!(interpreter.parse("x").is_err());
+    /// interpreter.eval("2").unwrap();
+    /// assert!(interpreter.run(first).is_err());
+    /// ```
+    pub fn parse(&mut self, source: &str) -> Result<Program> {
+        let before = self.parser.snapshot();
+        self.parser.parse_more(source)?;
+        let after = self.parser.snapshot();
+        self.parser.restore(before);
+        let tree = std::mem::take(&mut self.parser.ast);
+        let comments = std::mem::take(&mut self.parser.comments);
+        let dump = ast::dump(&tree);
+        let code = lower::lower(tree);
+        let (unoptimized, code) = if self.optimize {
+            let optimized = optimize::optimize(code.clone());
+            (Some(code), optimized)
+        } else {
+            (None, code)
+        };
+        Ok(Program {
+            tree: dump,
+            comments,
+            unoptimized,
+            code,
+            after: Some((after, self.version)),
+        })
+    }
+
+    pub fn run(&mut self, program: Program) -> Result<()> {
+        self.run_with(program, &mut ())
+    }
+
+    /// Runs `program` with an `Inspector` like the debugger looking at every step. If it fails, the
+    /// stack is put back to how it was before, and the variables and functions it declared are
+    /// forgotten.
+    #[doc(hidden)]
+    pub fn run_with(&mut self, program: Program, inspector: &mut impl Inspector) -> Result<()> {
+        if program.after.as_ref().is_some_and(|(_, version)| *version != self.version) {
+            return Err(anyhow!("the program was parsed before another program ran, so it must be parsed again"));
+        }
+        let stack = self.runtime.stack.clone();
+        let functions = self.runtime.functions.clone();
+        self.runtime.op_counter = self.code.len();
+        self.code.extend(program.code);
+        let result = eval::run_with(&mut self.runtime, &self.code, inspector);
+        if result.is_err() {
+            self.runtime.stack = stack;
+            self.runtime.functions = functions;
+            self.runtime.frames.clear();
+            self.runtime.return_stack.clear();
+            self.runtime.iterators.clear();
+            self.runtime.memory = self.runtime.count_memory();
+        } else {
+            if let Some((after, _)) = program.after {
+                self.parser.restore(after);
+            }
+            self.version += 1;
+        }
+        self.trim_code();
+        result
+    }
+
+    /// Throws away the code that can not run again. Only function bodies can be called later, and
+    /// they are moved to the front, so the code does not grow with every program that is run.
+    fn trim_code(&mut self) {
+        let mut functions: Vec<(usize, String)> =
+            self.runtime.functions.iter().map(|(name, address)| (*address, name.clone())).collect();
+        functions.sort();
+        let mut len = 0;
+        for (address, name) in functions {
+            let Some(pointer) = self.code[address].node.pointer() else {
+                continue;
+            };
+            // the `Function` jumps to the `FuncReturn` at the end of the body
+            let end = pointer.resolve(address) + 1;
+            self.code[len..end].rotate_left(address - len);
+            self.runtime.functions.insert(name, len);
+            len += end - address;
+        }
+        self.code.truncate(len);
+        self.runtime.op_counter = len;
+    }
+
+    /// Parses and runs `source`.
+    pub fn eval(&mut self, source: &str) -> Result<()> {
+        let program = self.parse(source)?;
+        self.run(program)
+    }
+
+    /// The stack, with the top of the stack last.
+    pub fn stack(&self) -> &[Value] {
+        &self.runtime.stack
+    }
+
+    /// The types of the values on the stack, as the parser knows them.
+    pub fn stack_types(&self) -> &[Type] {
+        &self.parser.type_stack
+    }
+
+    pub fn clear_stack(&mut self) {
+        self.runtime.stack.clear();
+        self.runtime.memory = self.runtime.count_memory();
+        self.parser.type_stack.clear();
+        self.version += 1;
+    }
+
+    /// Gives the variable `name` a value before running code that uses it. A new variable is
+    /// declared with `var`, while an existing one must keep its type and can not be a `konst`.
+    ///
+    /// ```
+    /// use ae_skarp::{Interpreter, Value};
+    ///
+    /// let mut interpreter = Interpreter::new();
+    /// interpreter.eval("konst k = 1").unwrap();
+    /// assert!(interpreter.set_var("k", Value::Int(5)).is_err());
+    /// assert_eq!(interpreter.var("k"), Some(&Value::Int(1)));
+    /// ```
+    pub fn set_var(&mut self, name: &str, value: Value) -> Result<()> {
+        let t = value
+            .type_of()
+            .ok_or_else(|| anyhow!("can not tell the type of {}, so '{}' can not be set to it", value.repr(), name))?;
+        let slot = match self.parser.variable(name) {
+            Some(Variable { mutable: false, .. }) => {
+                return Err(anyhow!("cannot assign to '{}' because it is a constant", name));
+            }
+            Some(variable) if variable.t != t => {
+                return Err(anyhow!("'{}' is {}, and can not be set to {}", name, variable.t, t));
+            }
+            Some(variable) => variable.slot,
+            None => {
+                let slot = self.parser.define(name, t, true)?;
+                self.version += 1;
+                slot
+            }
+        };
+        let Slot::Global(i) = slot else {
+            unreachable!("variables outside of code are global")
+        };
+        if self.runtime.mem.len() <= i {
+            self.runtime.mem.resize(i + 1, Value::Null);
+        }
+        self.runtime.mem[i] = value;
+        self.runtime.memory = self.runtime.count_memory();
+        Ok(())
+    }
+
+    /// The value of the variable `name`, if it has been set.
+    pub fn var(&self, name: &str) -> Option<&Value> {
+        let Slot::Global(i) = self.parser.variable(name)?.slot else {
+            return None;
+        };
+        self.runtime.mem.get(i).filter(|value| **value != Value::Null)
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+/// Output that is kept in memory for `Interpreter::take_output`.
+struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_function_bodies_are_kept() {
+        let mut interpreter = Interpreter::new();
+        interpreter.capture_output();
+        interpreter.eval("funk dobbel Htall -> Htall { 2 * }").unwrap();
+        assert_eq!(interpreter.code.len(), 4);
+        for i in 0..100 {
+            interpreter.eval(&format!("{} dobbel skrivnl", i)).unwrap();
+            assert!(interpreter.eval("1 0 / skrivnl").is_err());
+        }
+        assert_eq!(interpreter.code.len(), 4);
+
+        // a function from a program that fails is forgotten with the rest of the program
+        assert!(interpreter.eval("funk ny { }\n1 0 / skrivnl").is_err());
+        assert_eq!(interpreter.code.len(), 4);
+        interpreter.take_output();
+
+        interpreter.eval("funk tredobbel Htall -> Htall { 3 * }\n5 tredobbel dobbel skrivnl").unwrap();
+        // a function after other code is moved to the front
+        interpreter.eval("7 dobbel tredobbel skrivnl\nfunk ny { \"ny\" skrivnl }").unwrap();
+        interpreter.eval("ny").unwrap();
+        assert_eq!(interpreter.code.len(), 12);
+        assert_eq!(interpreter.take_output(), "30\n42\nny\n");
+    }
+
+    #[test]
+    fn programs_that_are_not_run_declare_nothing() {
+        let mut interpreter = Interpreter::new();
+        drop(interpreter.parse("var z = 1\nfunk f { }\n2").unwrap());
+        let err = interpreter.eval("z").unwrap_err();
+        assert_eq!(err.downcast_ref::<SourceError>().unwrap().message, "could not find identifier 'z'");
+        interpreter.eval("var z = 3\nfunk f { }").unwrap();
+        assert_eq!(interpreter.var("z"), Some(&Value::Int(3)));
+        assert_eq!(interpreter.stack_types(), &[]);
+    }
+
+    #[test]
+    fn programs_run_in_the_order_they_are_parsed() {
+        let mut interpreter = Interpreter::new();
+        let first = interpreter.parse("var a = 1").unwrap();
+        let second = interpreter.parse("var b = 2").unwrap();
+        interpreter.run(first).unwrap();
+        assert!(interpreter.run(second).is_err());
+
+        let program = interpreter.parse("a skrivnl").unwrap();
+        interpreter.clear_stack();
+        assert!(interpreter.run(program).is_err());
+        let program = interpreter.parse("a").unwrap();
+        interpreter.set_var("c", Value::Int(3)).unwrap();
+        assert!(interpreter.run(program).is_err());
+    }
+}
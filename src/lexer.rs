@@ -0,0 +1,265 @@
+use crate::utils::*;
+use anyhow::Result;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    /// Any run of characters that is not one of the other tokens, like names, keywords and operators.
+    Word(String),
+    OpenBrace,
+    CloseBrace,
+    OpenBracket,
+    CloseBracket,
+    Colon,
+    Comma,
+    Semicolon,
+    Eof,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenKind::Int(x) => write!(f, "{}", x),
+            TokenKind::Float(x) => write!(f, "{:?}", x),
+            TokenKind::Str(x) => write!(f, "\"{}\"", x),
+            TokenKind::Word(x) => write!(f, "{}", x),
+            TokenKind::OpenBrace => write!(f, "{{"),
+            TokenKind::CloseBrace => write!(f, "}}"),
+            TokenKind::OpenBracket => write!(f, "["),
+            TokenKind::CloseBracket => write!(f, "]"),
+            TokenKind::Colon => write!(f, ":"),
+            TokenKind::Comma => write!(f, ","),
+            TokenKind::Semicolon => write!(f, ";"),
+            TokenKind::Eof => write!(f, "end of file"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+    /// Whether the token follows the previous one without any whitespace in between, like the `[`
+    /// in `liste[0]`.
+    pub glued: bool,
+    /// Whether the token is the first one on its line.
+    pub line_start: bool,
+}
+
+pub struct Lexed {
+    /// The tokens of the code, always ending with `TokenKind::Eof`.
+    pub tokens: Vec<Token>,
+    pub comments: Vec<Comment>,
+    /// The leading whitespace of every line, where `indents[0]` is line 1.
+    pub indents: Vec<String>,
+}
+
+/// Character cursor that keeps track of the line and column of the next character.
+struct Cursor {
+    chars: Vec<char>,
+    index: usize,
+    pos: Span,
+}
+
+impl Cursor {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        self.chars.get(self.index + n).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.index += 1;
+        if c == '\n' {
+            self.pos.line += 1;
+            self.pos.col = 1;
+        } else {
+            self.pos.col += 1;
+        }
+        Some(c)
+    }
+
+    fn next_if(&mut self, func: impl FnOnce(&char) -> bool) -> Option<char> {
+        match self.peek() {
+            Some(c) if func(&c) => self.next(),
+            _ => None,
+        }
+    }
+}
+
+/// Splits `code` into tokens. `#` line comments and `#[ .. ]#` block comments outside of strings are
+/// left out of the tokens and returned separately, so tools like a formatter can put them back.
+pub fn lex(code: &str) -> Result<Lexed> {
+    let mut cursor = Cursor {
+        chars: code.chars().collect(),
+        index: 0,
+        pos: Span::new(1, 1),
+    };
+    let mut tokens: Vec<Token> = vec![];
+    let mut comments = vec![];
+    let mut glued = false;
+    let mut line = 0;
+    // end of file errors point right after the last token instead of at a line that may not exist
+    let mut end = cursor.pos;
+
+    while let Some(c) = cursor.peek() {
+        if c.is_whitespace() {
+            cursor.next();
+            glued = false;
+            continue;
+        }
+
+        let span = cursor.pos;
+        if c == '#' {
+            comments.push(lex_comment(&mut cursor)?);
+            end = cursor.pos;
+            glued = false;
+            continue;
+        }
+
+        let kind = match c {
+            '{' | '}' | '[' | ']' | ':' | ',' | ';' => {
+                cursor.next();
+                match c {
+                    '{' => TokenKind::OpenBrace,
+                    '}' => TokenKind::CloseBrace,
+                    '[' => TokenKind::OpenBracket,
+                    ']' => TokenKind::CloseBracket,
+                    ':' => TokenKind::Colon,
+                    ',' => TokenKind::Comma,
+                    _ => TokenKind::Semicolon,
+                }
+            }
+            '"' => lex_string(&mut cursor)?,
+            '0'..='9' => lex_number(&mut cursor)?,
+            '-' if cursor.peek_nth(1).is_some_and(|c| c.is_ascii_digit()) => lex_number(&mut cursor)?,
+            _ => lex_word(&mut cursor),
+        };
+        tokens.push(Token { kind, span, glued, line_start: span.line != line });
+        line = span.line;
+        end = cursor.pos;
+        glued = true;
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        span: end,
+        glued: false,
+        line_start: true,
+    });
+    let indents = code
+        .split('\n')
+        .map(|line| line.chars().take_while(|c| *c == ' ' || *c == '\t').collect())
+        .collect();
+    Ok(Lexed { tokens, comments, indents })
+}
+
+fn lex_comment(cursor: &mut Cursor) -> Result<Comment> {
+    let span = cursor.pos;
+    let block = cursor.peek_nth(1) == Some('[');
+    let mut text = String::new();
+    cursor.next();
+    if block {
+        cursor.next();
+        loop {
+            match cursor.next() {
+                Some(']') if cursor.peek() == Some('#') => {
+                    cursor.next();
+                    break;
+                }
+                Some(c) => text.push(c),
+                None => return Err(SourceError::at(span, "block comment is never closed, expected `]#`")),
+            }
+        }
+    } else {
+        while let Some(c) = cursor.next_if(|c| *c != '\n') {
+            text.push(c);
+        }
+    }
+    Ok(Comment { text, span, block })
+}
+
+fn lex_string(cursor: &mut Cursor) -> Result<TokenKind> {
+    let span = cursor.pos;
+    cursor.next();
+    let mut string = String::new();
+    loop {
+        match cursor.next() {
+            Some('"') => return Ok(TokenKind::Str(string)),
+            Some(c) => string.push(c),
+            None => return Err(SourceError::at(span, "String not closed")),
+        }
+    }
+}
+
+/// Lexes a number like `42`, `-7`, `2.5` or `1e-3`. A `..` right after the digits is left for the
+/// next token, so `0..10` becomes `0`, `..` and `10`.
+fn lex_number(cursor: &mut Cursor) -> Result<TokenKind> {
+    let span = cursor.pos;
+    let mut num_string = String::new();
+    if let Some(c) = cursor.next_if(|c| *c == '-') {
+        num_string.push(c);
+    }
+
+    let mut is_float = false;
+    let mut has_exponent = false;
+    loop {
+        match cursor.peek() {
+            Some('0'..='9') => num_string.push(cursor.next().unwrap()),
+            Some('.') if !is_float && cursor.peek_nth(1).is_some_and(|c| c.is_ascii_digit()) => {
+                is_float = true;
+                num_string.push(cursor.next().unwrap());
+            }
+            Some('e' | 'E') if !has_exponent && exponent_follows(cursor) => {
+                is_float = true;
+                has_exponent = true;
+                num_string.push(cursor.next().unwrap());
+                if let Some(sign) = cursor.next_if(|c| *c == '-' || *c == '+') {
+                    num_string.push(sign);
+                }
+            }
+            _ => break,
+        }
+    }
+
+    let invalid = || SourceError::at(span, format!("invalid number '{}'", num_string));
+    if is_float {
+        num_string.parse().map(TokenKind::Float).map_err(|_| invalid())
+    } else {
+        num_string.parse().map(TokenKind::Int).map_err(|_| invalid())
+    }
+}
+
+/// Checks if the `e` at the cursor starts an exponent, like in `1e9` or `2.5e-3`.
+fn exponent_follows(cursor: &Cursor) -> bool {
+    match cursor.peek_nth(1) {
+        Some('0'..='9') => true,
+        Some('-' | '+') => cursor.peek_nth(2).is_some_and(|c| c.is_ascii_digit()),
+        _ => false,
+    }
+}
+
+fn lex_word(cursor: &mut Cursor) -> TokenKind {
+    let mut word = String::new();
+    // `..` and `..=` end right away, so the end of a range like `0..n` or `a..b` is its own token
+    if cursor.peek() == Some('.') && cursor.peek_nth(1) == Some('.') {
+        word.extend(cursor.next());
+        word.extend(cursor.next());
+        word.extend(cursor.next_if(|c| *c == '='));
+        return TokenKind::Word(word);
+    }
+    while let Some(c) = cursor.next_if(|c| !c.is_whitespace() && !"{}[]:,;\"#".contains(*c)) {
+        word.push(c);
+        // a range starting with a name, like `a..b`, ends the word before the `..`
+        if cursor.peek() == Some('.') && cursor.peek_nth(1) == Some('.') {
+            break;
+        }
+    }
+    TokenKind::Word(word)
+}
@@ -0,0 +1,28 @@
+//! The æ# interpreter as a library. `Interpreter` is the way in: it parses and runs code, and
+//! gives back the stack, the variables and the output.
+
+mod interpreter;
+
+// The parts the interpreter is built from. The `ae-skarp` command uses them for the debugger,
+// `--trace` and `ae-skarp test`, but they are not part of the stable API.
+#[doc(hidden)]
+pub mod ast;
+#[doc(hidden)]
+pub mod bytecode;
+#[doc(hidden)]
+pub mod eval;
+#[doc(hidden)]
+pub mod lexer;
+#[doc(hidden)]
+pub mod lower;
+#[doc(hidden)]
+pub mod optimize;
+#[doc(hidden)]
+pub mod parser;
+#[doc(hidden)]
+pub mod utils;
+
+pub use interpreter::{Interpreter, Program};
+pub use utils::{
+    render_error, HostFunction, Limits, RuntimeError, RuntimeErrorKind, Signature, SourceError, Span, Type, Value,
+};
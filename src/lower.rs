@@ -0,0 +1,124 @@
+use crate::ast::*;
+use crate::utils::*;
+
+/// Turns the tree from the parser into the flat list of instructions `eval` runs. Jumps are emitted
+/// with labels, and every label is turned into a relative `JumpPointer` at the end.
+pub fn lower(ast: Vec<Ast>) -> Vec<Instr> {
+    let mut lowering = Lowering {
+        code: vec![],
+        labels: vec![],
+        jumps: vec![],
+    };
+    lowering.lower_all(ast);
+    lowering.resolve()
+}
+
+#[derive(Clone, Copy)]
+struct Label(usize);
+
+struct Lowering {
+    code: Vec<Instr>,
+    /// Where every label has been placed, as the index of the instruction that runs after the jump.
+    labels: Vec<Option<usize>>,
+    /// The instructions that jump to a label.
+    jumps: Vec<(usize, Label)>,
+}
+
+impl Lowering {
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn place(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    fn emit(&mut self, node: Node, span: Span) {
+        self.code.push(Instr::new(node, span));
+    }
+
+    /// Emits an instruction with a `JumpPointer` that is filled in once `target` is placed.
+    fn emit_jump(&mut self, node: Node, target: Label, span: Span) {
+        self.jumps.push((self.code.len(), target));
+        self.emit(node, span);
+    }
+
+    fn lower_all(&mut self, ast: Vec<Ast>) {
+        for node in ast {
+            self.lower_node(node);
+        }
+    }
+
+    fn lower_node(&mut self, node: Ast) {
+        let span = node.span;
+        match node.kind {
+            AstKind::Op(op) => self.emit(op, span),
+            AstKind::Call(name) => self.emit(Node::Call(name), span),
+            AstKind::Block(body) => self.lower_all(body),
+            AstKind::If { condition, then, else_ifs, otherwise } => {
+                let end = self.label();
+                let has_else = otherwise.is_some();
+                let mut branches = vec![(span, condition, then)];
+                branches.extend(else_ifs.into_iter().map(|branch| (branch.span, branch.condition, branch.body)));
+                let branch_count = branches.len();
+
+                for (i, (span, condition, body)) in branches.into_iter().enumerate() {
+                    let next = self.label();
+                    self.lower_all(condition);
+                    self.emit_jump(Node::JumpIfFalse(JumpPointer::new(0)), next, span);
+                    self.lower_all(body);
+                    if has_else || i + 1 < branch_count {
+                        self.emit_jump(Node::Jump(JumpPointer::new(0)), end, span);
+                    }
+                    self.place(next);
+                }
+                if let Some(otherwise) = otherwise {
+                    self.lower_all(otherwise);
+                }
+                self.place(end);
+                self.emit(Node::EndOfIf, span);
+            }
+            AstKind::While { condition, body } => {
+                let start = self.label();
+                let end = self.label();
+                self.place(start);
+                self.lower_all(condition);
+                self.emit_jump(Node::JumpIfFalse(JumpPointer::new(0)), end, span);
+                self.lower_all(body);
+                self.emit_jump(Node::Jump(JumpPointer::new(0)), start, span);
+                self.place(end);
+            }
+            AstKind::For { name, slot, body } => {
+                let next = self.label();
+                let end = self.label();
+                self.emit(Node::IterStart, span);
+                self.place(next);
+                self.emit_jump(Node::IterNext(name, slot, JumpPointer::new(0)), end, span);
+                self.lower_all(body);
+                self.emit_jump(Node::Jump(JumpPointer::new(0)), next, span);
+                self.place(end);
+            }
+            AstKind::Function { name, body } => {
+                let end = self.label();
+                self.emit_jump(Node::Function(name, JumpPointer::new(0)), end, span);
+                self.lower_all(body);
+                self.emit(Node::FuncReturn, span);
+                self.place(end);
+            }
+            AstKind::Test { .. } => {}
+        }
+    }
+
+    /// Fills in every jump. `eval` moves to the next instruction after a jump, so a jump from `from`
+    /// to `to` has the offset `to - from - 1`.
+    fn resolve(mut self) -> Vec<Instr> {
+        for (from, label) in self.jumps {
+            let to = self.labels[label.0].expect("every label is placed");
+            let offset = to as isize - from as isize - 1;
+            let pointer = self.code[from].node.pointer_mut().expect("only jumps are emitted with a label");
+            *pointer = JumpPointer::new(offset);
+        }
+        self.code
+    }
+}
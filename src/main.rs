@@ -1,42 +1,129 @@
-mod eval;
-mod parser;
-mod utils;
+mod debugger;
+mod repl;
+mod testing;
+mod trace;
 
+use ae_skarp::utils::Instr;
+use ae_skarp::{bytecode, render_error, Interpreter, Program};
 use anyhow::Result;
-use parser::Parser;
 use std::env;
-use std::fs::File;
-use std::io::{BufReader, Read};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process;
 
 
+/// The flags given on the command line.
+#[derive(Default)]
+struct Options {
+    debug_mode: bool,
+    emit_bytecode: bool,
+    no_optimize: bool,
+    step_debugger: bool,
+    /// Set by `--trace`, with the file to write to from `--trace=fil`.
+    trace: Option<Option<String>>,
+    trace_json: bool,
+}
+
 fn main() -> Result<()> {
-    let debug_flag: String = String::from("-d");
-    let debug_mode:bool;
-    let args = env::args().collect::<Vec<String>>();
-    let path: Option<&String>;
-
-    if Some(&debug_flag) == args.get(1){
-        path = args.get(2);
-        debug_mode = true
-    } else {
-        path = args.get(1);
-        debug_mode = false
+    let mut options = Options::default();
+    let mut paths = vec![];
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "-d" => options.debug_mode = true,
+            "--emit-bytecode" => options.emit_bytecode = true,
+            "--debug" => options.step_debugger = true,
+            "--trace" => options.trace = Some(options.trace.flatten()),
+            "--trace-json" => options.trace_json = true,
+            _ if arg.starts_with("--trace=") => {
+                options.trace = Some(Some(arg["--trace=".len()..].to_string()))
+            }
+            "-O0" => options.no_optimize = true,
+            "-O1" => options.no_optimize = false,
+            _ => paths.push(arg),
+        }
     }
 
-    if let Some(p) = path {
-        let mut source_code: String = String::from("");
-        BufReader::new(File::open(p)?).read_to_string(&mut source_code)?;
-        let ast = Parser::parse(source_code.chars().peekable(), None)?;
-        if debug_mode{
-            for (i, node) in ast.ast.iter().enumerate() {
-                println!("{}: {:?}", i, node)
+    if paths.first().is_some_and(|command| command == "test") {
+        if !testing::run_tests(&paths[1..], !options.no_optimize)? {
+            process::exit(1);
+        }
+    } else if let Some(p) = paths.first() {
+        let bytes = fs::read(p)?;
+        // precompiled files have no source to show next to an error
+        let source_code = if bytecode::is_bytecode(&bytes) {
+            String::new()
+        } else {
+            String::from_utf8(bytes.clone())?
+        };
+        let mut interpreter = Interpreter::new();
+        interpreter.set_optimize(!options.no_optimize);
+        let result = if options.emit_bytecode {
+            let out = match paths.get(1) {
+                Some(out) => out.clone(),
+                None => Path::new(p).with_extension("æsb").to_string_lossy().into_owned(),
+            };
+            compile(&mut interpreter, &source_code, &options)
+                .and_then(|program| Ok(fs::write(out, program.to_bytecode())?))
+        } else if bytecode::is_bytecode(&bytes) {
+            Program::from_bytecode(&bytes).and_then(|program| run(&mut interpreter, program, &options))
+        } else {
+            compile(&mut interpreter, &source_code, &options)
+                .and_then(|program| run(&mut interpreter, program, &options))
+        };
+        if let Err(err) = result {
+            match render_error(&err, p, &source_code) {
+                Some(rendered) => eprint!("{}", rendered),
+                None => eprintln!("error: {}\n --> {}", err, p),
             }
-            println!("\n");
+            process::exit(1);
         }
-    
-        eval::eval(ast.ast)?;
     } else {
-        println!("no argument given")
+        repl::start()?;
     }
     Ok(())
 }
+
+fn compile(interpreter: &mut Interpreter, source_code: &str, options: &Options) -> Result<Program> {
+    let program = interpreter.parse(source_code)?;
+    if options.debug_mode {
+        print!("{}", program.tree());
+        for comment in program.comments() {
+            println!("# {:?} ({})", comment.text, comment.span)
+        }
+        println!();
+        if let Some(unoptimized) = program.unoptimized() {
+            dump_code(unoptimized);
+            println!("-O1:");
+        }
+    }
+    Ok(program)
+}
+
+fn run(interpreter: &mut Interpreter, program: Program, options: &Options) -> Result<()> {
+    if options.debug_mode {
+        dump_code(program.code());
+        println!();
+    }
+
+    if options.step_debugger {
+        let mut debugger = debugger::Debugger::new(program.code());
+        interpreter.run_with(program, &mut debugger)
+    } else if options.trace.is_some() || options.trace_json {
+        let out: Box<dyn Write> = match options.trace.clone().flatten() {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(io::stderr()),
+        };
+        let mut tracer = trace::Tracer::new(out, options.trace_json);
+        interpreter.run_with(program, &mut tracer)
+    } else {
+        interpreter.run(program)
+    }
+}
+
+fn dump_code(code: &[Instr]) {
+    for (i, instr) in code.iter().enumerate() {
+        println!("{}: {:?} ({})", i, instr, instr.span)
+    }
+    println!();
+}
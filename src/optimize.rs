@@ -0,0 +1,345 @@
+use crate::utils::*;
+
+/// Makes lowered code faster without changing what it does. Constant arithmetic, comparisons and
+/// string concatenation are computed once here, stack shuffles that cancel out are removed, jumps
+/// to jumps go straight to the last target, and code that can never run is dropped.
+pub fn optimize(code: Vec<Instr>) -> Vec<Instr> {
+    let mut code: Vec<Step> = code
+        .into_iter()
+        .enumerate()
+        .map(|(i, instr)| Step {
+            target: instr.node.pointer().map(|pointer| pointer.resolve(i).wrapping_add(1)),
+            instr,
+        })
+        .collect();
+
+    collapse_jump_chains(&mut code);
+    loop {
+        let len = code.len();
+        code = remove_unreachable(code);
+        code = remove_useless_jumps(code);
+        code = peephole(code);
+        if code.len() == len {
+            break;
+        }
+    }
+
+    code.into_iter()
+        .enumerate()
+        .map(|(i, mut step)| {
+            if let (Some(target), Some(pointer)) = (step.target, step.instr.node.pointer_mut()) {
+                *pointer = JumpPointer::new(target as isize - i as isize - 1);
+            }
+            step.instr
+        })
+        .collect()
+}
+
+/// An instruction with the index of the instruction its jump continues at, which is easier to move
+/// around than a relative `JumpPointer`.
+struct Step {
+    instr: Instr,
+    target: Option<usize>,
+}
+
+/// Points every jump that lands on a `Jump` at where that one goes instead.
+fn collapse_jump_chains(code: &mut [Step]) {
+    for i in 0..code.len() {
+        let Some(mut target) = code[i].target else { continue };
+        // a loop of jumps never gets anywhere, so stop after visiting every instruction once
+        for _ in 0..code.len() {
+            match code.get(target) {
+                Some(Step { instr: Instr { node: Node::Jump(_), .. }, target: Some(next) }) => target = *next,
+                _ => break,
+            }
+        }
+        code[i].target = Some(target);
+    }
+}
+
+/// Removes the instructions no path through the code reaches, like the ones after a `Jump` that
+/// nothing jumps to.
+fn remove_unreachable(code: Vec<Step>) -> Vec<Step> {
+    let mut reachable = vec![false; code.len()];
+    let mut todo = vec![0];
+    while let Some(i) = todo.pop() {
+        if i >= code.len() || reachable[i] {
+            continue;
+        }
+        reachable[i] = true;
+        match code[i].instr.node {
+            Node::Jump(_) => {}
+            Node::FuncReturn => {}
+            // the body of a function is reached by `Call`, which comes back after the call, so it
+            // is kept as if it followed the `Function` that jumps over it
+            Node::Function(..) => todo.push(i + 1),
+            _ => todo.push(i + 1),
+        }
+        todo.extend(code[i].target);
+    }
+
+    let mut reachable = reachable.into_iter();
+    remove(code, |_| !reachable.next().unwrap())
+}
+
+/// Removes jumps to the instruction right after them.
+fn remove_useless_jumps(code: Vec<Step>) -> Vec<Step> {
+    let mut i = 0;
+    remove(code, |step| {
+        i += 1;
+        matches!(step.instr.node, Node::Jump(_)) && step.target == Some(i)
+    })
+}
+
+/// Removes the steps `filter` returns true for, and moves the targets of jumps to match. A jump to
+/// a removed step goes to the step after it.
+fn remove(code: Vec<Step>, mut filter: impl FnMut(&Step) -> bool) -> Vec<Step> {
+    let mut new_index = Vec::with_capacity(code.len() + 1);
+    let mut out = vec![];
+    for step in code {
+        new_index.push(out.len());
+        if !filter(&step) {
+            out.push(step);
+        }
+    }
+    new_index.push(out.len());
+    retarget(&mut out, &new_index);
+    out
+}
+
+fn retarget(code: &mut [Step], new_index: &[usize]) {
+    for step in code {
+        if let Some(target) = &mut step.target {
+            *target = new_index[*target];
+        }
+    }
+}
+
+/// Folds constants and removes stack shuffles that cancel out, by looking at the last few
+/// instructions every time one is added. Instructions are only merged when nothing jumps into the
+/// middle of them.
+fn peephole(code: Vec<Step>) -> Vec<Step> {
+    let mut is_target = vec![false; code.len() + 1];
+    for step in &code {
+        if let Some(target) = step.target {
+            is_target[target] = true;
+        }
+    }
+
+    let mut new_index = Vec::with_capacity(code.len() + 1);
+    let mut out: Vec<Step> = vec![];
+    // whether something jumps to each instruction in `out`
+    let mut labels: Vec<bool> = vec![];
+    // set when instructions that were jumped to are removed, so the next instruction is jumped to
+    let mut label_next = false;
+    for (i, step) in code.into_iter().enumerate() {
+        new_index.push(out.len());
+        out.push(step);
+        labels.push(is_target[i] || label_next);
+        label_next = false;
+
+        while let Some((len, replacement)) = simplify(&out, &labels) {
+            let start = out.len() - len;
+            let label = labels[start];
+            let span = out.last().unwrap().instr.span;
+            out.truncate(start);
+            labels.truncate(start);
+            match replacement {
+                Some(node) => {
+                    out.push(Step { instr: Instr::new(node, span), target: None });
+                    labels.push(label);
+                }
+                None => label_next = label,
+            }
+        }
+    }
+    new_index.push(out.len());
+    retarget(&mut out, &new_index);
+    out
+}
+
+/// Looks for a pattern at the end of `code`, returning how many instructions it covers and what to
+/// put there instead.
+fn simplify(code: &[Step], labels: &[bool]) -> Option<(usize, Option<Node>)> {
+    let nodes: Vec<&Node> = code.iter().rev().take(3).map(|step| &step.instr.node).rev().collect();
+    // nothing may jump to the instructions after the first one of a pattern
+    let free = |len: usize| len <= code.len() && !labels[labels.len() + 1 - len..].contains(&true);
+
+    match nodes[..] {
+        [.., Node::Operator(Op::Dup), Node::Operator(Op::Drop)]
+        | [.., Node::Operator(Op::Over), Node::Operator(Op::Drop)]
+        | [.., Node::Operator(Op::Swap), Node::Operator(Op::Swap)]
+            if free(2) =>
+        {
+            return Some((2, None));
+        }
+        [.., a, Node::Operator(Op::Drop)] if constant(a).is_some() && free(2) => return Some((2, None)),
+        _ => {}
+    }
+
+    let Some(Node::Operator(op)) = nodes.last() else {
+        return None;
+    };
+    let arity = op.arity();
+    if nodes.len() <= arity || !free(arity + 1) {
+        return None;
+    }
+    let args: Option<Vec<Value>> = nodes[nodes.len() - 1 - arity..nodes.len() - 1]
+        .iter()
+        .map(|node| constant(node))
+        .collect();
+    let value = fold(*op, &args?)?;
+    Some((arity + 1, Some(push(value))))
+}
+
+fn constant(node: &Node) -> Option<Value> {
+    match node {
+        Node::PushInt(x) => Some(Value::Int(*x)),
+        Node::PushFloat(x) => Some(Value::Float(*x)),
+        Node::PushBool(x) => Some(Value::Bool(*x)),
+        Node::PushStr(x) => Some(Value::Str(x.clone())),
+        _ => None,
+    }
+}
+
+fn push(value: Value) -> Node {
+    match value {
+        Value::Int(x) => Node::PushInt(x),
+        Value::Float(x) => Node::PushFloat(x),
+        Value::Bool(x) => Node::PushBool(x),
+        Value::Str(x) => Node::PushStr(x),
+        _ => unreachable!("only values that can be pushed are folded"),
+    }
+}
+
+/// Computes `op` at compile time. Integer arithmetic that would overflow or divide by zero is left
+/// for `eval`, so the error is reported when the program runs.
+fn fold(op: Op, args: &[Value]) -> Option<Value> {
+    use Value::*;
+    match (op, args) {
+        (Op::SumInt, [Int(a), Int(b)]) => a.checked_add(*b).map(Int),
+        (Op::SubInt, [Int(a), Int(b)]) => a.checked_sub(*b).map(Int),
+        (Op::MultInt, [Int(a), Int(b)]) => a.checked_mul(*b).map(Int),
+        (Op::DivInt, [Int(a), Int(b)]) => a.checked_div(*b).map(Int),
+        (Op::ModInt, [Int(a), Int(b)]) => a.checked_rem(*b).map(Int),
+
+        (Op::SumFloat, [Float(a), Float(b)]) => Some(Float(a + b)),
+        (Op::SubFloat, [Float(a), Float(b)]) => Some(Float(a - b)),
+        (Op::MultFloat, [Float(a), Float(b)]) => Some(Float(a * b)),
+        (Op::DivFloat, [Float(a), Float(b)]) => Some(Float(a / b)),
+        (Op::ModFloat, [Float(a), Float(b)]) => Some(Float(a % b)),
+
+        (Op::ConcatStr, [Str(a), Str(b)]) => Some(Str(a.to_owned() + b)),
+
+        (Op::EqInt, [Int(a), Int(b)]) => Some(Bool(a == b)),
+        (Op::LtInt, [Int(a), Int(b)]) => Some(Bool(a < b)),
+        (Op::GtInt, [Int(a), Int(b)]) => Some(Bool(a > b)),
+        (Op::LqInt, [Int(a), Int(b)]) => Some(Bool(a <= b)),
+        (Op::GqInt, [Int(a), Int(b)]) => Some(Bool(a >= b)),
+        (Op::EqFloat, [Float(a), Float(b)]) => Some(Bool(a == b)),
+        (Op::LtFloat, [Float(a), Float(b)]) => Some(Bool(a < b)),
+        (Op::GtFloat, [Float(a), Float(b)]) => Some(Bool(a > b)),
+        (Op::LqFloat, [Float(a), Float(b)]) => Some(Bool(a <= b)),
+        (Op::GqFloat, [Float(a), Float(b)]) => Some(Bool(a >= b)),
+        (Op::AndBool, [Bool(a), Bool(b)]) => Some(Bool(*a && *b)),
+
+        (Op::IntToFloat, [Int(a)]) => Some(Float(*a as f64)),
+        (Op::FloatToInt, [Float(a)]) => Some(Int(*a as i64)),
+        (Op::StrLen, [Str(a)]) => Some(Int(a.chars().count() as i64)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lower, parser::Parser, Interpreter};
+
+    /// Optimises `source` and checks the instructions it gives, and that it prints `expected` both
+    /// with and without the optimiser.
+    fn check(source: &str, instructions: &[&str], expected: &str) {
+        let code = optimize(lower::lower(Parser::parse(source, None).unwrap().ast));
+        let dump: Vec<String> = code.iter().map(|instr| format!("{:?}", instr)).collect();
+        assert_eq!(dump, instructions);
+
+        for optimize in [false, true] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_optimize(optimize);
+            interpreter.capture_output();
+            interpreter.eval(source).unwrap();
+            assert_eq!(interpreter.take_output(), expected, "optimize: {}", optimize);
+        }
+    }
+
+    #[test]
+    fn if_else_if_chain() {
+        // the condition of the `ellvis` is jumped to, and is folded with the jump going to the result
+        check(
+            "hvis 1 2 > { \"a\" skrivnl } ellvis 2 2 == { \"b\" skrivnl } ellers { \"c\" skrivnl }",
+            &[
+                "false", "Jnt(3)", "\"a\"", "Println", "Jmp(7)", "true", "Jnt(3)", "\"b\"", "Println", "Jmp(2)",
+                "\"c\"", "Println", "EndOfIf",
+            ],
+            "b\n",
+        );
+    }
+
+    #[test]
+    fn nested_for_loops() {
+        // each loop clears its variable when it ends, so the inner loop ends at the clear of `y`
+        // and not at the jump back to the outer loop
+        check(
+            "for hver x i 0..2 {\n    for hver y i 0..2 {\n        x 10 * y + skrivnl\n    }\n}",
+            &[
+                "0", "2", "RangeExclusive", "IterStart", "IterNext(x@g0, 14)", "0", "2", "RangeExclusive",
+                "IterStart", "IterNext(y@g1, 7)", "x@g0", "10", "MultInt", "y@g1", "SumInt", "Println", "Jmp(-8)",
+                "Clear(y@g1)", "Jmp(-15)", "Clear(x@g0)",
+            ],
+            "0\n1\n10\n11\n",
+        );
+    }
+
+    #[test]
+    fn nested_while_loops() {
+        // the inner loop ends at the jump back to the outer loop, so it goes straight to the outer
+        // condition, and the outer jump back is never reached
+        check(
+            "var i = 0\nvar j = 0\nnår i 2 < {\n    i = i 1 +\n    j = 0\n    når j 2 < {\n        \
+             i 10 * j + skrivnl\n        j = j 1 +\n    }\n}",
+            &[
+                "0", "Store(i@g0)", "0", "Store(j@g1)", "i@g0", "2", "LtInt", "Jnt(21)", "i@g0", "1", "SumInt",
+                "Store(i@g0)", "0", "Store(j@g1)", "j@g1", "2", "LtInt", "Jnt(-14)", "i@g0", "10", "MultInt", "j@g1",
+                "SumInt", "Println", "j@g1", "1", "SumInt", "Store(j@g1)", "Jmp(-15)",
+            ],
+            "10\n11\n20\n21\n",
+        );
+    }
+
+    #[test]
+    fn function_bodies() {
+        // bodies are kept even when nothing calls them, and `Funk` still jumps over the folded body
+        check(
+            "funk ganger_seks Htall -> Htall { 2 3 * * }\nfunk ubrukt { \"aldri\" skrivnl }\n4 ganger_seks skrivnl",
+            &[
+                "Funk(ganger_seks, 3)", "6", "MultInt", "FnRet", "Funk(ubrukt, 3)", "\"aldri\"", "Println", "FnRet",
+                "4", "Call(ganger_seks)", "Println",
+            ],
+            "24\n",
+        );
+    }
+
+    #[test]
+    fn folded_jump_target() {
+        // the loop jumps back to `1 2 <`, which becomes `true`, while `3 +` after the `hvis` is not
+        // folded with the `2` before it since the `ellers` jumps to the `3`
+        check(
+            "var i = 0\nnår 1 2 < i 3 < og { i skrivnl i = i 1 + }\nhvis i 3 == { 1 } ellers { 2 } 3 + skrivnl",
+            &[
+                "0", "Store(i@g0)", "true", "i@g0", "3", "LtInt", "AndBool", "Jnt(7)", "i@g0", "Println", "i@g0",
+                "1", "SumInt", "Store(i@g0)", "Jmp(-13)", "i@g0", "3", "EqInt", "Jnt(2)", "1", "Jmp(1)", "2",
+                "EndOfIf", "3", "SumInt", "Println",
+            ],
+            "0\n1\n2\n4\n",
+        );
+    }
+}
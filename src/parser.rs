@@ -1,281 +1,1196 @@
+use crate::ast::*;
+use crate::lexer::{lex, Token, TokenKind};
 use crate::utils::*;
-use anyhow::{anyhow, Result, Ok};
-use std::{iter::Peekable, str::Chars};
+use anyhow::{Result, Ok};
+use std::collections::HashMap;
 
-pub struct Parser<'a> {
-    code: Peekable<Chars<'a>>,
-    pub ast: Vec<Node>,
+/// Where the code of an indented block or a value that continues over several lines ends: at the
+/// first line that is not indented deeper than `outer`.
+#[derive(Clone)]
+struct Boundary {
+    outer: String,
+    /// The indentation of the lines of an indented block. Lines of a value may be indented any
+    /// amount, so values have none.
+    inner: Option<String>,
+}
+
+pub struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    indents: Vec<String>,
+    boundary: Option<Boundary>,
+    pub ast: Vec<Ast>,
     pub type_stack: Vec<Type>,
+    pub functions: HashMap<String, Signature>,
+    /// Functions written in Rust, added with `Interpreter::register`.
+    pub host_functions: HashMap<String, Signature>,
+    pub comments: Vec<Comment>,
+    scopes: Vec<HashMap<String, Variable>>,
+    next_slot: usize,
+    in_function: bool,
+    nested: bool,
+    span: Span,
+}
+
+/// What the parser knew at one point, from `Parser::snapshot`.
+pub struct Snapshot {
+    type_stack: Vec<Type>,
+    functions: HashMap<String, Signature>,
+    scopes: Vec<HashMap<String, Variable>>,
+    next_slot: usize,
 }
 
-impl Parser<'_> {
-    pub fn parse(code: Peekable<Chars>, context: Option<Vec<Type>>) -> Result<Parser> {
+const KEYWORDS: &[&str] = &[
+    "sann", "usann", "når", "hvis", "ellvis", "ellers", "var", "konst", "funk", "for", "hver",
+    "gitt", "test",
+];
+const BUILTINS: &[&str] = &[
+    "og", "dup", "slipp", "snu", "over", "skrivnl", "til_ftall", "til_htall", "lengde", "legg_til",
+    "ta_ut", "påstå", "påstå_lik",
+];
+
+impl Parser {
+    pub fn parse(code: &str, context: Option<Vec<Type>>) -> Result<Parser> {
+        let lexed = lex(code)?;
         let mut parser = Parser {
-            code,
+            tokens: lexed.tokens,
+            pos: 0,
+            indents: lexed.indents,
+            boundary: None,
             ast: vec![],
-            type_stack: if let Some(ctx) = context { ctx } else { vec![] },
+            nested: context.is_some(),
+            type_stack: context.unwrap_or_default(),
+            functions: HashMap::new(),
+            host_functions: HashMap::new(),
+            comments: lexed.comments,
+            scopes: vec![HashMap::new()],
+            next_slot: 0,
+            in_function: false,
+            span: Span::new(1, 1),
         };
-        loop {
-            match parser.code.peek() {
-                Some('0'..='9') => parser.parse_number()?,
-                Some('-') => parser.parse_number()?,
-                Some(c) if c.is_whitespace() => {
-                    parser.code.next();
+        parser.parse_all()?;
+        parser.expect_end(TokenKind::Eof)?;
+        Ok(parser)
+    }
+
+    /// Parses more code after the code that was parsed before, like the next input in the REPL. The
+    /// variables, functions and stack from before can be used, and `ast` only holds the new code. If
+    /// the new code has an error, the parser is left as it was.
+    pub fn parse_more(&mut self, code: &str) -> Result<()> {
+        let lexed = lex(code)?;
+        self.tokens = lexed.tokens;
+        self.pos = 0;
+        self.indents = lexed.indents;
+        self.comments = lexed.comments;
+        self.ast.clear();
+
+        let saved = self.snapshot();
+        let result = self.parse_all().and_then(|_| self.expect_end(TokenKind::Eof));
+        if result.is_err() {
+            self.restore(saved);
+            self.ast.clear();
+            self.boundary = None;
+            self.in_function = false;
+        }
+        result
+    }
+
+    /// Saves the stack, variables and functions known so far, to go back to with `restore` if the
+    /// code parsed after this fails.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            type_stack: self.type_stack.clone(),
+            functions: self.functions.clone(),
+            scopes: self.scopes.clone(),
+            next_slot: self.next_slot,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.type_stack = snapshot.type_stack;
+        self.functions = snapshot.functions;
+        self.scopes = snapshot.scopes;
+        self.next_slot = snapshot.next_slot;
+    }
+
+    /// Declares a variable at the top level from outside the code, like `Interpreter::set_var`.
+    pub fn define(&mut self, name: &str, t: Type, mutable: bool) -> Result<Slot> {
+        if !is_valid_name(name) {
+            return Err(anyhow::anyhow!("'{}' is not a valid variable name", name));
+        }
+        if self.functions.contains_key(name) || self.host_functions.contains_key(name) {
+            return Err(anyhow::anyhow!("'{}' is already a function", name));
+        }
+        // between two pieces of code the parser is always at the top level
+        self.declare(name, t, mutable)
+    }
+
+    /// Makes `name` call a function written in Rust. Like a `funk`, it can not share its name with
+    /// another function or a variable.
+    pub fn define_host_function(&mut self, name: &str, signature: Signature) -> Result<()> {
+        if !is_valid_name(name) {
+            return Err(anyhow::anyhow!("'{}' is not a valid function name", name));
+        }
+        if self.functions.contains_key(name) || self.host_functions.contains_key(name) {
+            return Err(anyhow::anyhow!("function '{}' is already defined", name));
+        }
+        if self.lookup(name).is_some() {
+            return Err(anyhow::anyhow!("'{}' is already a variable", name));
+        }
+        self.host_functions.insert(name.to_string(), signature);
+        Ok(())
+    }
+
+    /// Finds the variable `name` refers to at the end of the code parsed so far.
+    pub fn variable(&self, name: &str) -> Option<&Variable> {
+        self.lookup(name)
+    }
+
+    /// Parses code up to the next token that ends it, starting from `context`, and returns the code
+    /// and the stack it leaves. The code gets its own scope inside the current one.
+    fn parse_nested(&mut self, context: Vec<Type>) -> Result<(Vec<Ast>, Vec<Type>)> {
+        let ast = std::mem::take(&mut self.ast);
+        let type_stack = std::mem::replace(&mut self.type_stack, context);
+        let (next_slot, nested) = (self.next_slot, self.nested);
+        self.scopes.push(HashMap::new());
+        self.nested = true;
+
+        let result = self.parse_all();
+
+        let mut clears = self.pop_scope();
+        // the frame of a function is dropped when it returns, so its body needs no clearing
+        if !nested && self.in_function {
+            clears.clear();
+        }
+        self.next_slot = next_slot;
+        self.nested = nested;
+        let types = std::mem::replace(&mut self.type_stack, type_stack);
+        let mut nested_ast = std::mem::replace(&mut self.ast, ast);
+        result?;
+        nested_ast.append(&mut clears);
+        Ok((nested_ast, types))
+    }
+
+    /// Runs `parse` with `boundary` as the end of the code, and puts the old one back afterwards.
+    fn with_boundary<T>(
+        &mut self,
+        boundary: Option<Boundary>,
+        parse: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let outer = std::mem::replace(&mut self.boundary, boundary);
+        let result = parse(self);
+        self.boundary = outer;
+        result
+    }
+
+    /// Removes the innermost scope, and returns the code that clears the slots of its variables so
+    /// their values do not stay in memory after the scope has ended.
+    fn pop_scope(&mut self) -> Vec<Ast> {
+        let scope = self.scopes.pop().expect("there is always a scope");
+        let mut variables = scope.into_iter().map(|(name, variable)| (variable.slot, name)).collect::<Vec<_>>();
+        variables.sort_by_key(|(slot, _)| match slot {
+            Slot::Global(i) | Slot::Local(i) => *i,
+        });
+        variables
+            .into_iter()
+            .map(|(slot, name)| Ast::new(AstKind::Op(Node::Clear(name, slot)), self.span))
+            .collect()
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Variable> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    /// Adds a variable to the innermost scope and gives it the next free slot. A variable may shadow
+    /// one from an outer scope, but not one from the same scope.
+    fn declare(&mut self, name: &str, t: Type, mutable: bool) -> Result<Slot> {
+        if self.functions.contains_key(name) || self.host_functions.contains_key(name) {
+            return Err(SourceError::at(self.span, format!("'{}' is already a function", name)));
+        }
+        let scope = self.scopes.last_mut().expect("there is always a scope");
+        if scope.contains_key(name) {
+            return Err(SourceError::at(self.span, format!("'{}' is already defined in this scope", name)));
+        }
+        let slot = if self.in_function {
+            Slot::Local(self.next_slot)
+        } else {
+            Slot::Global(self.next_slot)
+        };
+        self.next_slot += 1;
+        scope.insert(name.to_string(), Variable { t, mutable, slot });
+        Ok(slot)
+    }
+
+    fn emit(&mut self, node: Node) {
+        self.ast.push(Ast::new(AstKind::Op(node), self.span));
+    }
+
+    fn error(&self, message: impl Into<String>) -> anyhow::Error {
+        SourceError::at(self.span, message)
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+
+    fn peek_nth(&self, n: usize) -> &Token {
+        &self.tokens[(self.pos + n).min(self.tokens.len() - 1)]
+    }
+
+    /// Moves to the next token and returns the current one. The cursor stays at the end of the file.
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn peek_is_word(&self, word: &str) -> bool {
+        matches!(&self.peek().kind, TokenKind::Word(w) if w == word)
+    }
+
+    fn indent(&self, line: usize) -> &str {
+        self.indents.get(line - 1).map_or("", |indent| indent.as_str())
+    }
+
+    /// Checks if the next token is on a line that is outside the current indented block or value.
+    fn at_boundary(&self) -> Result<bool> {
+        let token = self.peek();
+        let Some(boundary) = &self.boundary else {
+            return Ok(false);
+        };
+        if !token.line_start || token.kind == TokenKind::Eof {
+            return Ok(false);
+        }
+
+        let indent = self.indent(token.span.line);
+        let inconsistent = || SourceError::at(token.span, "inconsistent use of tabs and spaces in indentation");
+        if indent.starts_with(&boundary.outer) && indent.len() > boundary.outer.len() {
+            return match &boundary.inner {
+                Some(inner) if !indent.starts_with(inner.as_str()) => Err(if inner.starts_with(indent) {
+                    SourceError::at(token.span, "unindent does not match any outer indentation level")
+                } else {
+                    inconsistent()
+                }),
+                _ => Ok(false),
+            };
+        }
+        if boundary.inner.is_some() && !boundary.outer.starts_with(indent) {
+            return Err(inconsistent());
+        }
+        Ok(true)
+    }
+
+    /// Checks if the next token ends the code being parsed, which is handled by whoever started it.
+    fn at_end(&self) -> Result<bool> {
+        let ends = matches!(
+            self.peek().kind,
+            TokenKind::Eof
+                | TokenKind::OpenBrace
+                | TokenKind::CloseBrace
+                | TokenKind::CloseBracket
+                | TokenKind::Colon
+                | TokenKind::Comma
+                | TokenKind::Semicolon
+        );
+        Ok(ends || self.at_boundary()?)
+    }
+
+    /// Checks that the code stopped at `expected`, and moves past it.
+    fn expect_end(&mut self, expected: TokenKind) -> Result<()> {
+        let token = self.peek();
+        if token.kind == expected {
+            self.next();
+            return Ok(());
+        }
+        Err(SourceError::at(token.span, format!("unexpected '{}'", token.kind)))
+    }
+
+    fn parse_all(&mut self) -> Result<()> {
+        while !self.at_end()? {
+            self.parse_term()?;
+        }
+        Ok(())
+    }
+
+    fn parse_term(&mut self) -> Result<()> {
+        let token = self.next();
+        self.span = token.span;
+        match token.kind {
+            TokenKind::Int(x) => {
+                self.emit(Node::PushInt(x));
+                self.type_stack.push(Type::Int);
+                if self.range_follows() {
+                    return self.parse_range_literal();
+                }
+                self.parse_index_suffix()
+            }
+            TokenKind::Float(x) => {
+                self.emit(Node::PushFloat(x));
+                self.type_stack.push(Type::Float);
+                self.parse_index_suffix()
+            }
+            TokenKind::Str(x) => {
+                self.emit(Node::PushStr(x));
+                self.type_stack.push(Type::Str);
+                self.parse_index_suffix()
+            }
+            TokenKind::OpenBracket => self.parse_list(),
+            TokenKind::Word(word) => {
+                self.parse_word(&word)?;
+                // a range can start with a variable too, like `a..b`
+                if self.range_follows() {
+                    return self.parse_range_literal();
                 }
-                Some('"') => parser.parse_string()?,
-                Some(_) => parser.parse_word(None)?,
-                None => break,
+                Ok(())
             }
+            kind => Err(self.error(format!("unexpected '{}'", kind))),
         }
-        Ok(parser)
     }
 
-    fn parse_number(&mut self) -> Result<()> {
-        let mut num_string = String::from("");
-        loop {
-            match self.code.peek() {
-                Some('-') => {
-                    num_string.push(self.code.next().unwrap());
-                    if let Some('0'..='9') = self.code.peek() {
-                    } else {
-                        return self.parse_word(Some(num_string));
-                    }
+    fn range_follows(&self) -> bool {
+        let token = self.peek();
+        token.glued && matches!(&token.kind, TokenKind::Word(w) if w == ".." || w == "..=")
+    }
+
+    /// Parses the rest of a range literal like `0..10`, `1..=n` or `a..b`, where the start has been
+    /// parsed.
+    fn parse_range_literal(&mut self) -> Result<()> {
+        let token = self.next();
+        let TokenKind::Word(op) = token.kind else {
+            unreachable!("checked by range_follows")
+        };
+        let span = token.span;
+
+        let len = self.type_stack.len();
+        if self.peek().glued && !self.at_end()? {
+            self.parse_term()?;
+        }
+        if self.type_stack.len() != len + 1 || self.type_stack.last() != Some(&Type::Int) {
+            return Err(SourceError::at(span, format!("end of range after `{}` must be a {}", op, Type::Int)));
+        }
+
+        self.span = span;
+        self.parse_operator(&op)?;
+        Ok(())
+    }
+
+    /// Parses a list literal like `[1, 2, 3]`, or `[T; n]` for a list of `n` default values of type `T`.
+    /// The `[` has already been read.
+    fn parse_list(&mut self) -> Result<()> {
+        let span = self.span;
+        self.with_boundary(None, |parser| parser.parse_list_elements(span))?;
+        self.parse_index_suffix()
+    }
+
+    fn parse_list_elements(&mut self, span: Span) -> Result<()> {
+        if self.peek().kind == TokenKind::CloseBracket {
+            return Err(self.error("empty list literal needs a type, write it as [T; 0]"));
+        }
+
+        let start = self.pos;
+        let default = self.read_type().filter(|(name, _)| Type::from_name(name).is_some());
+        let element_type = match default {
+            Some((name, _)) if self.peek().kind == TokenKind::Semicolon => {
+                self.emit_default(&name).unwrap()
+            }
+            _ => {
+                self.pos = start;
+                let (mut value, t) = self.parse_single("list element")?;
+                self.ast.append(&mut value);
+                if self.peek().kind != TokenKind::Semicolon {
+                    return self.parse_more_elements(span, t);
                 }
-                Some('0'..='9') => num_string.push(self.code.next().unwrap()),
-                _ => break,
+                t
             }
+        };
+
+        self.next();
+        let token = self.next();
+        let TokenKind::Int(len @ 0..) = token.kind else {
+            return Err(SourceError::at(token.span, format!("list length must be a number, but found '{}'", token.kind)));
+        };
+        self.close_bracket(span)?;
+        self.span = span;
+        self.emit(Node::RepeatList(len as usize));
+        self.type_stack.push(Type::List(Box::new(element_type)));
+        Ok(())
+    }
+
+    /// Parses the elements after the first one in a list literal, which must all be of type `element`.
+    fn parse_more_elements(&mut self, span: Span, element: Type) -> Result<()> {
+        let mut len = 1;
+        while self.peek().kind == TokenKind::Comma {
+            self.next();
+            let element_start = self.peek().span;
+            let (mut value, t) = self.parse_single("list element")?;
+            if t != element {
+                return Err(SourceError::at(element_start, format!(
+                    "list elements must have the same type: expected {}, but found {}",
+                    element, t
+                )));
+            }
+            self.ast.append(&mut value);
+            len += 1;
         }
-        self.ast.push(Node::PushInt(num_string.parse()?));
-        self.type_stack.push(Type::Int);
+
+        self.close_bracket(span)?;
+        self.span = span;
+        self.emit(Node::MakeList(len));
+        self.type_stack.push(Type::List(Box::new(element)));
         Ok(())
     }
 
-    fn parse_string(&mut self) -> Result<()> {
-        self.code.next();
-        let mut string = "".to_string();
-        loop {
-            match self.code.peek() {
-                Some('"') => break,
-                Some(_) => string.push(self.code.next().unwrap()),
-                None => return Err(anyhow!("String not closed"))
+    fn close_bracket(&mut self, open: Span) -> Result<()> {
+        if self.peek().kind == TokenKind::Eof {
+            return Err(SourceError::at(open, "No closing ']' found"));
+        }
+        self.expect_end(TokenKind::CloseBracket)
+    }
+
+    /// Parses code that must push exactly one value on top of the current stack.
+    fn parse_single(&mut self, what: &str) -> Result<(Vec<Ast>, Type)> {
+        let start = self.peek().span;
+        let (ast, mut types) = self.parse_nested(self.type_stack.clone())?;
+        let t = types.pop();
+        match t {
+            Some(t) if types == self.type_stack => Ok((ast, t)),
+            _ => {
+                let mut types = types;
+                types.extend(t);
+                Err(SourceError::at(start, format!(
+                    "{} must leave exactly one value on the stack, but the stack went from {} to {}",
+                    what,
+                    type_list(&self.type_stack),
+                    type_list(&types)
+                )))
+            }
+        }
+    }
+
+    /// Emits the default value for the type called `name`, where `[T; n]` gives a list of `n`
+    /// default values of `T`. Returns `None` without emitting anything if `name` is not a type.
+    fn emit_default(&mut self, name: &str) -> Option<Type> {
+        let name = name.trim();
+        if let Some(inner) = name.strip_prefix('[').and_then(|n| n.strip_suffix(']')) {
+            let (element, len) = inner.rsplit_once(';')?;
+            let len = len.trim().parse().ok()?;
+            Type::from_name(element)?;
+            let t = self.emit_default(element)?;
+            self.emit(Node::RepeatList(len));
+            return Some(Type::List(Box::new(t)));
+        }
+
+        let t = Type::from_name(name)?;
+        match &t {
+            Type::Int => self.emit(Node::PushInt(0)),
+            Type::Float => self.emit(Node::PushFloat(0.0)),
+            Type::Bool => self.emit(Node::PushBool(false)),
+            Type::Str => self.emit(Node::PushStr(String::new())),
+            Type::List(_) => self.emit(Node::MakeList(0)),
+            Type::Range => {
+                self.emit(Node::PushInt(0));
+                self.emit(Node::PushInt(0));
+                self.emit(Node::Operator(Op::RangeExclusive));
+            }
+        }
+        Some(t)
+    }
+
+    /// Parses any number of `[i]` directly following a value, indexing into a list or string.
+    fn parse_index_suffix(&mut self) -> Result<()> {
+        while self.peek().kind == TokenKind::OpenBracket && self.peek().glued {
+            let span = self.next().span;
+            self.span = span;
+            let element = match self.type_stack.last() {
+                Some(Type::List(t)) => (**t).clone(),
+                Some(Type::Str) => Type::Str,
+                Some(t) => return Err(self.error(format!("cannot index into {}", t))),
+                None => return Err(self.error("cannot index into an empty stack")),
+            };
+            let load = self.last_load();
+            let mut index = self.parse_index(span)?;
+            self.span = span;
+            match load {
+                // `navn[i]` reads the element straight from the variable instead of copying the list
+                Some((name, slot)) if index.iter().all(|ast| matches!(ast.kind, AstKind::Op(_))) => {
+                    self.ast.pop();
+                    self.ast.append(&mut index);
+                    self.emit(Node::LoadIndex(name, slot));
+                }
+                _ => {
+                    self.ast.append(&mut index);
+                    self.emit(Node::Index);
+                }
             }
+            self.type_stack.pop();
+            self.type_stack.push(element);
         }
-        self.code.next();
-        self.ast.push(Node::PushStr(string));
-        self.type_stack.push(Type::Str);
         Ok(())
     }
 
-    fn parse_word(&mut self, optional_start: Option<String>) -> Result<()> {
-        let mut word = if let Some(start) = optional_start {
-            start
-        } else {
-            String::from("")
+    /// Parses the index and the `]` after a `[` at `open`.
+    fn parse_index(&mut self, open: Span) -> Result<Vec<Ast>> {
+        let start = self.peek().span;
+        let (index, t) = self.with_boundary(None, |parser| {
+            let index = parser.parse_single("index")?;
+            parser.close_bracket(open)?;
+            Ok(index)
+        })?;
+        if t != Type::Int {
+            return Err(SourceError::at(start, format!("index must be {}, but found {}", Type::Int, t)));
+        }
+        Ok(index)
+    }
+
+    /// Parses `navn[i] = verdi`, where `navn` has already been read.
+    fn parse_index_assignment(&mut self, name: &str) -> Result<()> {
+        let span = self.span;
+        let (element, slot) = match self.lookup(name) {
+            Some(Variable { mutable: false, .. }) => {
+                return Err(self.error(format!("cannot assign to '{}' because it is a constant", name)))
+            }
+            Some(Variable { t: Type::List(t), slot, .. }) => ((**t).clone(), *slot),
+            Some(Variable { t, .. }) => return Err(self.error(format!("cannot assign to an index of {}", t))),
+            None => return Err(self.error(format!("could not find list '{}'", name))),
         };
 
-        loop {
-            match self.code.peek() {
-                Some(c) if c.is_whitespace() => break,
-                Some(_) => word.push(self.code.next().unwrap()),
-                None => break,
+        let open = self.next().span;
+        let mut index = self.parse_index(open)?;
+
+        self.next();
+        let value_start = self.peek().span;
+        let (mut value, mut types) = self.parse_value(span)?;
+        if types.pop().as_ref() != Some(&element) || types != self.type_stack {
+            return Err(SourceError::at(value_start, format!(
+                "value assigned to '{}[..]' must be a single {}",
+                name, element
+            )));
+        }
+
+        self.ast.append(&mut index);
+        self.ast.append(&mut value);
+        self.span = span;
+        self.emit(Node::SetIndex(name.to_string(), slot));
+        Ok(())
+    }
+
+    /// Checks if the next tokens are `[..] =`, the rest of an index assignment.
+    fn index_assignment_follows(&self) -> bool {
+        let mut depth = 0;
+        for (i, token) in self.tokens[self.pos..].iter().enumerate() {
+            match token.kind {
+                TokenKind::OpenBracket => depth += 1,
+                TokenKind::CloseBracket => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return matches!(&self.peek_nth(i + 1).kind, TokenKind::Word(w) if w == "=");
+                    }
+                }
+                TokenKind::Eof => return false,
+                _ => (),
             }
         }
+        false
+    }
+
+    fn parse_word(&mut self, word: &str) -> Result<()> {
+        if self.peek().kind == TokenKind::OpenBracket && self.peek().glued && self.index_assignment_follows() {
+            return self.parse_index_assignment(word);
+        }
+        if is_valid_name(word) && self.peek_is_word("=") {
+            return self.parse_assignment(word);
+        }
 
-        if word.is_empty() {
+        self.parse_word_value(word)?;
+        self.parse_index_suffix()
+    }
+
+    fn parse_word_value(&mut self, word: &str) -> Result<()> {
+        let operator_res = self.parse_operator(word)?;
+        if operator_res {
             return Ok(());
         }
 
-        let operator_res = self.parse_operator(&word)?;
-        if operator_res == true {
+        let keyword_res = self.parse_keyword(word)?;
+        if keyword_res {
             return Ok(());
         }
 
-        let keyword_res = self.parse_keyword(&word)?;
-        if keyword_res == true {
+        if let Some(signature) = self.functions.get(word).cloned() {
+            self.check_call(word, &signature)?;
+            self.ast.push(Ast::new(AstKind::Call(word.to_string()), self.span));
+            return Ok(());
+        }
+        if let Some(signature) = self.host_functions.get(word).cloned() {
+            self.check_call(word, &signature)?;
+            self.emit(Node::CallHost(word.to_string()));
             return Ok(());
         }
-        self.parse_identifier(&word)?;
+        self.parse_identifier(word)?;
         Ok(())
     }
 
-    fn parse_keyword(&mut self, word: &String) -> Result<bool> {
-        match word.as_str() {
+    fn parse_keyword(&mut self, word: &str) -> Result<bool> {
+        match word {
             "usann" => {
-                self.ast.push(Node::PushBool(false));
+                self.emit(Node::PushBool(false));
                 self.type_stack.push(Type::Bool);
                 Ok(true)
             }
             "sann" => {
-                self.ast.push(Node::PushBool(true));
+                self.emit(Node::PushBool(true));
                 self.type_stack.push(Type::Bool);
                 Ok(true)
             }
             "når" => {
-                let mut condition = self.parse_condition()?;
-                let mut block = self.parse_block()?;
-                let condition_start = -(block.len() as isize + condition.len() as isize + 2);
-
-                self.ast.append(&mut condition);
-                self.ast
-                    .push(Node::JumpIfFalse(JumpPointer::new(block.len() as isize + 1)));
-
-                self.ast.append(&mut block);
-                self.ast.push(Node::Jump(JumpPointer::new(condition_start)));
-
+                let span = self.span;
+                let condition = self.parse_condition()?;
+                let (body, block_types) = self.parse_block()?;
+                if block_types != self.type_stack {
+                    return Err(SourceError::at(span, format!(
+                        "the body of `når` must leave the stack unchanged: expected {}, but it leaves {}",
+                        type_list(&self.type_stack),
+                        type_list(&block_types)
+                    )));
+                }
+                self.ast.push(Ast::new(AstKind::While { condition, body }, span));
                 Ok(true)
             }
             "hvis" => {
-                let mut condition = self.parse_condition()?;
-                let mut block = self.parse_block()?;
+                self.parse_if_chain()?;
+                Ok(true)
+            }
+            "for" => {
+                self.parse_for()?;
+                Ok(true)
+            }
+            "gitt" => {
+                self.parse_gitt()?;
+                Ok(true)
+            }
+            "hver" => Err(self.error("`hver` can only be used in `for hver x i ..`")),
+            "ellvis" => Err(self.error("`ellvis` can only follow a `hvis` block")),
+            "ellers" => Err(self.error("`ellers` can only follow a `hvis` block")),
+            "var" => {
+                self.parse_declaration(true)?;
+                Ok(true)
+            }
+            "konst" => {
+                self.parse_declaration(false)?;
+                Ok(true)
+            }
+            "funk" => {
+                if self.nested {
+                    return Err(self.error("functions can only be defined at the top level"));
+                }
+
+                let span = self.span;
+                let TokenKind::Word(name) = self.peek().kind.clone() else {
+                    return Err(self.error("Expected function name after 'funk'"));
+                };
+                self.next();
+                if !is_valid_name(&name) {
+                    return Err(self.error(format!("'{}' can not be used as a function name", name)));
+                }
+                if self.functions.contains_key(&name) || self.host_functions.contains_key(&name) {
+                    return Err(self.error(format!("function '{}' is already defined", name)));
+                }
+                if self.lookup(&name).is_some() {
+                    return Err(self.error(format!("'{}' is already a variable", name)));
+                }
 
-                self.ast.append(&mut condition);
-                self.ast
-                    .push(Node::JumpIfFalse(JumpPointer::new(block.len() as isize + 1)));
+                let signature = self.parse_signature(&name)?;
+                // registered before the body is parsed so the function can call itself
+                self.functions.insert(name.clone(), signature.clone());
 
-                self.ast.append(&mut block);
-                self.ast.push(Node::EndOfIf);
+                let (next_slot, in_function) = (self.next_slot, self.in_function);
+                self.next_slot = 0;
+                self.in_function = true;
+                let body = self.parse_block_with(signature.inputs.clone());
+                self.next_slot = next_slot;
+                self.in_function = in_function;
+                let (body, result) = body?;
+                self.span = span;
+                if result != signature.outputs {
+                    return Err(self.error(format!(
+                        "function '{}' should leave {} on the stack, but leaves {}",
+                        name,
+                        type_list(&signature.outputs),
+                        type_list(&result)
+                    )));
+                }
 
+                self.ast.push(Ast::new(AstKind::Function { name, body }, span));
                 Ok(true)
             }
-            "ellvis" => {
-                if let Some(Node::EndOfIf) = self.ast.last() {
-                    self.ast.pop();
-                    let mut condition = self.parse_condition()?;
-                    let mut block = self.parse_block()?;
-                    self.ast.push(Node::Jump(JumpPointer::new((condition.len() + block.len()) as isize + 1)));
-    
-                    self.ast.append(&mut condition);
-                    self.ast
-                        .push(Node::JumpIfFalse(JumpPointer::new(block.len() as isize + 1)));
-    
-                    self.ast.append(&mut block);
-                    self.ast.push(Node::EndOfIf);
-    
-                    Ok(true)
-                } else {
-                    Err(anyhow!("Elif block can only end if block"))
+            "test" => {
+                if self.nested {
+                    return Err(self.error("tests can only be defined at the top level"));
                 }
+
+                let span = self.span;
+                let TokenKind::Str(name) = self.peek().kind.clone() else {
+                    return Err(self.error("expected the name of the test as a string after 'test'"));
+                };
+                self.next();
+                let (body, _) = self.parse_block_with(vec![])?;
+                self.span = span;
+                self.ast.push(Ast::new(AstKind::Test { name, body }, span));
+                Ok(true)
             }
-            "ellers" => {
-                self.remove_whitespace();
-                if let Some(Node::EndOfIf) = self.ast.last() {
-                    let mut block = self.parse_block()?;
-                    self.ast.pop();
-                    self.ast
-                        .push(Node::Jump(JumpPointer::new(block.len() as isize)));
-                    self.ast.append(&mut block);
+            _ => Ok(false),
+        }
+    }
 
-                    Ok(true)
-                } else {
-                    Err(anyhow!("Else block can only end if block"))
-                }
+    /// Reads a type name like `Htall`, `Liste<Htall>` or `[Htall; 5]`, without checking that the type
+    /// exists. Returns `None` if the tokens can not be a type.
+    fn read_type(&mut self) -> Option<(String, Span)> {
+        let token = self.peek().clone();
+        match token.kind {
+            TokenKind::Word(name) => {
+                self.next();
+                Some((name, token.span))
             }
-            "var" => {
-                let name = if let Node::Identifier(name) = self.get_name()? {
-                    Some(name)
-                } else {
-                    None
+            TokenKind::OpenBracket => {
+                self.next();
+                let (element, _) = self.read_type()?;
+                if self.next().kind != TokenKind::Semicolon {
+                    return None;
+                }
+                let TokenKind::Int(len) = self.next().kind else {
+                    return None;
                 };
+                if self.next().kind != TokenKind::CloseBracket {
+                    return None;
+                }
+                Some((format!("[{}; {}]", element, len), token.span))
+            }
+            _ => None,
+        }
+    }
 
-                self.remove_whitespace();
-                
-                if self.code.peek().unwrap() != &'=' {
-                    return Err(anyhow!("Expected '=' after constant name, but found '{}'", self.code.peek().unwrap()))
-                } else {
-                    self.code.next();
+    fn parse_signature(&mut self, name: &str) -> Result<Signature> {
+        let mut signature = Signature {
+            inputs: vec![],
+            outputs: vec![],
+        };
+        let mut seen_arrow = false;
+        loop {
+            let token = self.peek().clone();
+            self.span = token.span;
+            if self.block_follows() {
+                break;
+            }
+            if token.kind == TokenKind::Eof {
+                return Err(self.error("No block found"));
+            }
+
+            let word = self.read_type().map(|(word, _)| word);
+            if word.as_deref() == Some("->") {
+                if seen_arrow {
+                    return Err(self.error(format!("function '{}' has more than one '->'", name)));
                 }
+                seen_arrow = true;
+                continue;
+            }
 
-                self.remove_whitespace();
+            let Some(t) = word.as_deref().and_then(Type::from_name) else {
+                let word = word.unwrap_or_else(|| token.kind.to_string());
+                return Err(self.error(format!("unknown type '{}' in signature of function '{}'", word, name)));
+            };
+            if seen_arrow {
+                signature.outputs.push(t)
+            } else {
+                signature.inputs.push(t)
+            }
+        }
+        Ok(signature)
+    }
 
-                let mut value = if self.code.peek() == Some(&'{') {
-                    self.parse_block()?
-                } else {
-                    let mut exper = "".to_string();
+    /// Replaces the inputs of the function on the type stack with its outputs.
+    fn check_call(&mut self, name: &str, signature: &Signature) -> Result<()> {
+        if !self.type_stack.ends_with(&signature.inputs) {
+            return Err(self.error(format!(
+                "function '{}' expects {} on top of the stack, but found {}",
+                name,
+                type_list(&signature.inputs),
+                type_list(&self.type_stack)
+            )));
+        }
+        self.type_stack.truncate(self.type_stack.len() - signature.inputs.len());
+        self.type_stack.extend(signature.outputs.iter().cloned());
+        Ok(())
+    }
 
-                    while self.code.peek() != Some(&'\n'){
-                        exper.push(self.code.next().unwrap())
-                    }
-                    let exper_parser = Parser::parse(exper.chars().peekable(), Some(self.type_stack.clone()))?;
-                    exper_parser.ast
-                };
+    fn get_name(&mut self) -> Result<String> {
+        let token = self.next();
+        self.span = token.span;
+        match token.kind {
+            TokenKind::Word(name) if is_valid_name(&name) => Ok(name),
+            kind => Err(self.error(format!("Expected identifier but found '{}'", kind))),
+        }
+    }
 
-                self.ast.push(Node::DefineConst(name.clone().expect("Identifier not found")));
-                self.ast.append(&mut value);
-                self.ast.push(Node::Return(name.clone().expect("Identifier not found")));
-                Ok(true)
+    /// Parses `var navn: Type = verdi` or `konst navn: Type = verdi`, where the type is optional.
+    /// A `var` with a type may leave out the value to start with the default value of the type.
+    fn parse_declaration(&mut self, mutable: bool) -> Result<()> {
+        let span = self.span;
+        let keyword = if mutable { "var" } else { "konst" };
+        let name = self.get_name()?;
 
-            }
-            _ => Ok(false),
+        let mut annotation = None;
+        if self.peek().kind == TokenKind::Colon {
+            self.next();
+            self.span = self.peek().span;
+            let type_name = match self.read_type() {
+                Some((type_name, _)) if Type::from_name(&type_name).is_some() => type_name,
+                Some((type_name, _)) => return Err(self.error(format!("unknown type '{}'", type_name))),
+                None => return Err(self.error(format!("unknown type '{}'", self.peek().kind))),
+            };
+            annotation = Some((type_name, self.span));
         }
+
+        self.span = self.peek().span;
+        let (mut value, t) = if self.peek_is_word("=") {
+            self.next();
+            let value_start = self.peek().span;
+            let (value, mut types) = self.parse_value(span)?;
+            let t = types.pop();
+            let Some(t) = t.filter(|_| types == self.type_stack) else {
+                return Err(SourceError::at(value_start, format!(
+                    "value of '{}' must leave exactly one value on the stack",
+                    name
+                )));
+            };
+            if let Some((type_name, type_span)) = &annotation {
+                let expected = Type::from_name(type_name).unwrap();
+                if t != expected {
+                    return Err(SourceError::at(value_start, format!(
+                        "'{}' is declared as {} at {}, but the value is {}",
+                        name, expected, type_span, t
+                    )));
+                }
+            }
+            (value, t)
+        } else if let (true, Some((type_name, type_span))) = (mutable, &annotation) {
+            self.span = *type_span;
+            let ast = std::mem::take(&mut self.ast);
+            let t = self.emit_default(type_name).unwrap();
+            (std::mem::replace(&mut self.ast, ast), t)
+        } else if mutable {
+            return Err(self.error(format!("Expected '=' or a type after `var {}`", name)));
+        } else {
+            return Err(self.error(format!("Expected '=' after `{} {}`", keyword, name)));
+        };
+
+        self.span = span;
+        let slot = self.declare(&name, t, mutable)?;
+        self.ast.append(&mut value);
+        self.emit(Node::Store(name, slot));
+        Ok(())
     }
 
-    fn remove_whitespace(&mut self) {
-        while self.code.peek().unwrap().is_whitespace() {
-            self.code.next();
+    /// Parses `navn = verdi` for a variable declared with `var`.
+    fn parse_assignment(&mut self, name: &str) -> Result<()> {
+        let span = self.span;
+        let (expected, slot) = match self.lookup(name) {
+            Some(Variable { mutable: false, .. }) => {
+                return Err(self.error(format!("cannot assign to '{}' because it is a constant", name)))
+            }
+            Some(variable) => (variable.t.clone(), variable.slot),
+            None => return Err(self.error(format!("could not find variable '{}'", name))),
+        };
+
+        self.next();
+        let value_start = self.peek().span;
+        let (mut value, mut types) = self.parse_value(span)?;
+        if types.pop().as_ref() != Some(&expected) || types != self.type_stack {
+            return Err(SourceError::at(value_start, format!(
+                "value assigned to '{}' must be a single {}",
+                name, expected
+            )));
+        }
+
+        self.span = span;
+        // `navn = navn verdi legg_til` adds to the list in the variable instead of copying it
+        if let [first, rest @ .., last] = &value[..] {
+            let loads_itself = matches!(&first.kind, AstKind::Op(Node::Load(n, s)) if n == name && *s == slot);
+            let pushes = matches!(last.kind, AstKind::Op(Node::Operator(Op::ListPush)));
+            if loads_itself && pushes && pushes_one_value(rest) {
+                value.pop();
+                self.ast.extend(value.drain(1..));
+                self.emit(Node::Append(name.to_string(), slot));
+                return Ok(());
+            }
         }
+        self.ast.append(&mut value);
+        self.emit(Node::Store(name.to_string(), slot));
+        Ok(())
     }
 
-    fn get_name(&mut self) -> Result<Node> {
-        self.remove_whitespace();
-        let mut name = "".to_string();
-        while !self.code.peek().unwrap().is_whitespace() {
-            name.push(self.code.next().unwrap())
+    /// Parses the value after a `=`, which is either a block or the rest of the statement starting at
+    /// `statement`. The value may go on over the following lines as long as they are indented deeper.
+    fn parse_value(&mut self, statement: Span) -> Result<(Vec<Ast>, Vec<Type>)> {
+        if self.peek().kind == TokenKind::OpenBrace {
+            return self.parse_block();
         }
+        let boundary = Boundary {
+            outer: self.indent(statement.line).to_string(),
+            inner: None,
+        };
+        self.with_boundary(Some(boundary), |parser| parser.parse_nested(parser.type_stack.clone()))
+    }
 
-        let _ = self.parse_word(Some(name));
+    /// Parses an `hvis` together with any `ellvis` and `ellers` blocks following it. Every branch
+    /// must leave the same types on the stack, and without an `ellers` that means leaving it unchanged.
+    fn parse_if_chain(&mut self) -> Result<()> {
+        let if_span = self.span;
+        let entry = self.type_stack.clone();
+        let mut branches = vec![];
+        let condition = self.parse_condition()?;
+        let (block, types) = self.parse_block()?;
+        branches.push((if_span, condition, block, types));
 
-        if let Some(Node::Identifier(_))= self.ast.last() {
-            Ok(self.ast.pop().unwrap())
-        } else {
-            Err(anyhow!("Expected identifier but found '{:?}'", self.ast.pop().unwrap()))
+        let mut otherwise = None;
+        while !self.at_boundary()? {
+            if self.peek_is_word("ellvis") {
+                let span = self.next().span;
+                let condition = self.parse_condition()?;
+                let (block, types) = self.parse_block()?;
+                branches.push((span, condition, block, types));
+            } else if self.peek_is_word("ellers") {
+                let span = self.next().span;
+                let (block, types) = self.parse_block()?;
+                otherwise = Some((span, block, types));
+                break;
+            } else {
+                break;
+            }
         }
+
+        let result = match &otherwise {
+            Some((_, _, types)) => types.clone(),
+            None => entry.clone(),
+        };
+        for (span, _, _, types) in &branches {
+            if *types == result {
+                continue;
+            }
+            return Err(if otherwise.is_some() {
+                SourceError::at(*span, format!(
+                    "all branches must leave the same types on the stack: `ellers` leaves {}, but this branch leaves {}",
+                    type_list(&result),
+                    type_list(types)
+                ))
+            } else {
+                SourceError::at(*span, format!(
+                    "`hvis` without `ellers` must leave the stack unchanged: expected {}, but this branch leaves {}",
+                    type_list(&entry),
+                    type_list(types)
+                ))
+            });
+        }
+
+        let mut branches = branches.into_iter();
+        let (_, condition, then, _) = branches.next().expect("there is always an `hvis` branch");
+        let else_ifs = branches
+            .map(|(span, condition, body, _)| ElseIf { condition, body, span })
+            .collect();
+        let otherwise = otherwise.map(|(_, body, _)| body);
+        self.ast.push(Ast::new(AstKind::If { condition, then, else_ifs, otherwise }, if_span));
+
+        self.type_stack = result;
+        Ok(())
     }
 
-    fn parse_block(&mut self) -> Result<Vec<Node>> {
-        let mut block = String::from("");
-        if let Some('{') = self.code.peek() {
-            self.code.next();
-            let mut bracket_count = 1;
-            while bracket_count > 0 {
-                match self.code.next() {
-                    Some('{') => {
-                        bracket_count += 1;
-                        block.push('{')
-                    }
-                    Some('}') => {
-                        bracket_count -= 1;
-                        if bracket_count != 0 {
-                            block.push('}')
-                        }
-                    }
-                    Some(x) => {
-                        block.push(x);
-                    }
-                    None => return Err(anyhow!("No ending bracket found")),
-                };
+    /// Parses `for hver x i <iterable> { .. }`, where the iterable is a range, list or string and
+    /// `x` is a constant that only exists inside the body.
+    fn parse_for(&mut self) -> Result<()> {
+        let span = self.span;
+        self.span = self.peek().span;
+        if !self.peek_is_word("hver") {
+            return Err(self.error("Expected `hver` after `for`"));
+        }
+        self.next();
+        let name = self.get_name()?;
+        self.span = self.peek().span;
+        if !self.peek_is_word("i") {
+            return Err(self.error(format!("Expected `i` after `for hver {}`", name)));
+        }
+        self.next();
+
+        // without an expression after `i`, the loop takes the iterable from the top of the stack
+        let start = self.peek().span;
+        let (mut iterable, t) = if self.block_follows() {
+            match self.type_stack.pop() {
+                Some(t) => (vec![], t),
+                None => return Err(SourceError::at(start, "nothing to loop over, the stack is empty")),
             }
         } else {
-            return Err(anyhow!("No block found"));
+            self.parse_single("iterable")?
+        };
+        let element = match &t {
+            Type::Range => Type::Int,
+            Type::List(element) => (**element).clone(),
+            Type::Str => Type::Str,
+            _ => return Err(SourceError::at(start, format!("cannot loop over {}", t))),
+        };
+
+        let next_slot = self.next_slot;
+        self.scopes.push(HashMap::new());
+        let slot = self.declare(&name, element, false)?;
+        let body = self.parse_block();
+        let mut clears = self.pop_scope();
+        self.next_slot = next_slot;
+        let (body, body_types) = body?;
+        if body_types != self.type_stack {
+            return Err(SourceError::at(span, format!(
+                "the body of `for` must leave the stack unchanged: expected {}, but it leaves {}",
+                type_list(&self.type_stack),
+                type_list(&body_types)
+            )));
         }
-        Ok(Parser::parse(block.chars().peekable(), Some(self.type_stack.clone()))?.ast)
+
+        self.ast.append(&mut iterable);
+        self.ast.push(Ast::new(AstKind::For { name, slot, body }, span));
+        self.ast.append(&mut clears);
+        Ok(())
     }
 
-    fn parse_condition(&mut self) -> Result<Vec<Node>> {
-        let mut condition = String::from("");
+    /// Parses `gitt x: Htall, y: Htall { .. }`, which pops one value per name off the stack into
+    /// constants that only exist inside the block. The last name gets the value on top of the stack.
+    fn parse_gitt(&mut self) -> Result<()> {
+        let span = self.span;
+        let mut bindings = vec![];
         loop {
-            match self.code.peek() {
-                Some('{') => break,
-                Some(_) => condition.push(self.code.next().unwrap()),
-                None => return Err(anyhow!("No block found")),
+            let name = self.get_name()?;
+            let name_span = self.span;
+            if self.peek().kind != TokenKind::Colon {
+                return Err(SourceError::at(self.peek().span, format!(
+                    "Expected `navn: Type` in `gitt`, but found '{}' after '{}'",
+                    self.peek().kind,
+                    name
+                )));
+            }
+            self.next();
+            let type_span = self.peek().span;
+            let t = match self.read_type() {
+                Some((type_name, _)) => Type::from_name(&type_name).ok_or(type_name),
+                None => Err(self.peek().kind.to_string()),
+            };
+            let t = t.map_err(|type_name| SourceError::at(type_span, format!("unknown type '{}'", type_name)))?;
+            bindings.push((name, t, name_span));
+            if self.peek().kind != TokenKind::Comma {
+                break;
+            }
+            self.next();
+        }
+
+        let expected = bindings.iter().map(|(_, t, _)| t.clone()).collect::<Vec<_>>();
+        let found_at = self.type_stack.len().saturating_sub(expected.len());
+        let found = &self.type_stack[found_at..];
+        if found != expected.as_slice() {
+            return Err(SourceError::at(span, format!(
+                "`gitt` expects {} on the stack, but found {}",
+                type_list(&expected),
+                type_list(found)
+            )));
+        }
+        self.type_stack.truncate(found_at);
+
+        let next_slot = self.next_slot;
+        self.scopes.push(HashMap::new());
+        let mut stores = vec![];
+        for (name, t, name_span) in bindings {
+            self.span = name_span;
+            let slot = self.declare(&name, t, false)?;
+            stores.push(Ast::new(AstKind::Op(Node::Store(name, slot)), name_span));
+        }
+        let body = self.parse_block();
+        let mut clears = self.pop_scope();
+        self.next_slot = next_slot;
+        let (mut body, body_types) = body?;
+
+        // the top of the stack belongs to the last name, so the values are stored back to front
+        stores.reverse();
+        stores.append(&mut body);
+        stores.append(&mut clears);
+        self.ast.push(Ast::new(AstKind::Block(stores), span));
+        self.type_stack = body_types;
+        Ok(())
+    }
+
+    /// Checks if the next token starts a block, which is either a `{` or a `:` at the end of a line.
+    fn block_follows(&self) -> bool {
+        match self.peek().kind {
+            TokenKind::OpenBrace => true,
+            TokenKind::Colon => {
+                let next = self.peek_nth(1);
+                next.kind == TokenKind::Eof || next.span.line > self.peek().span.line
             }
+            _ => false,
         }
-        let mut condition_parser =
-            Parser::parse(condition.chars().peekable(), Some(self.type_stack.clone()))?;
-        let condition_ast = condition_parser.ast;
+    }
 
-        if let Some(Type::Bool) = condition_parser.type_stack.pop() {
-            if condition_parser.type_stack != self.type_stack {
-                return Err(anyhow!(
+    fn parse_block(&mut self) -> Result<(Vec<Ast>, Vec<Type>)> {
+        self.parse_block_with(self.type_stack.clone())
+    }
+
+    /// Parses a `{ .. }` block, or a block that starts with a `:` at the end of a line and holds every
+    /// following line that is indented deeper than the line with the `:`.
+    fn parse_block_with(&mut self, context: Vec<Type>) -> Result<(Vec<Ast>, Vec<Type>)> {
+        let token = self.peek().clone();
+        self.span = token.span;
+        if !self.block_follows() {
+            return Err(self.error("No block found"));
+        }
+        self.next();
+
+        if token.kind == TokenKind::OpenBrace {
+            let block = self.with_boundary(None, |parser| parser.parse_nested(context))?;
+            if self.peek().kind == TokenKind::Eof {
+                return Err(SourceError::at(token.span, "No ending bracket found"));
+            }
+            self.expect_end(TokenKind::CloseBrace)?;
+            return Ok(block);
+        }
+
+        let outer = self.indent(token.span.line).to_string();
+        let first = self.peek().clone();
+        let indent = self.indent(first.span.line).to_string();
+        if first.kind == TokenKind::Eof || !(indent.starts_with(&outer) && indent.len() > outer.len()) {
+            if first.kind == TokenKind::Eof || outer.starts_with(&indent) {
+                return Err(SourceError::at(first.span, "expected an indented block after ':'"));
+            }
+            return Err(SourceError::at(first.span, "inconsistent use of tabs and spaces in indentation"));
+        }
+
+        let boundary = Boundary { outer, inner: Some(indent) };
+        self.with_boundary(Some(boundary), |parser| {
+            let block = parser.parse_nested(context)?;
+            match parser.peek().kind {
+                TokenKind::Eof | TokenKind::CloseBrace => (),
+                _ if parser.at_boundary()? => (),
+                _ => parser.expect_end(TokenKind::Eof)?,
+            }
+            Ok(block)
+        })
+    }
+
+    fn parse_condition(&mut self) -> Result<Vec<Ast>> {
+        let start = self.peek().span;
+        let (condition_ast, mut condition_types) = self.parse_nested(self.type_stack.clone())?;
+        if !self.block_follows() {
+            self.span = self.peek().span;
+            return Err(self.error("No block found"));
+        }
+        self.span = start;
+
+        if let Some(Type::Bool) = condition_types.pop() {
+            if condition_types != self.type_stack {
+                return Err(self.error(
                     "condition must only leave a bool at the top of the stack"
                 ));
             }
@@ -283,425 +1198,499 @@ impl Parser<'_> {
             if !condition_ast.is_empty() {
                 Ok(condition_ast)
             } else {
-                Err(anyhow!("no condition found"))
+                Err(self.error("no condition found"))
             }
         } else {
-            Err(anyhow!(
+            Err(self.error(
                 "condition must leave a bool on the top of the stack"
             ))
         }
     }
 
-    fn parse_operator(&mut self, word: &String) -> Result<bool> {
-        match word.as_str() {
+    fn parse_operator(&mut self, word: &str) -> Result<bool> {
+        match word {
             "+" => {
-                let b = self.type_stack.pop().unwrap();
-                let a = self.type_stack.pop().unwrap();
-                match (a, b) {
+                let [a, b] = self.pop_args(word)?;
+                match (&a, &b) {
                     (Type::Int, Type::Int) => {
-                        self.ast.push(Node::Operator {
-                            op: Op::SumInt,
-                            arity: 2,
-                            func: |args| match (args.get(0), args.get(1)) {
-                                (Some(Value::Int(a)), Some(Value::Int(b))) => {
-                                    Some(vec![Value::Int(a + b)])
-                                }
-                                _ => None,
-                            },
-                        });
+                        self.emit(Node::Operator(Op::SumInt));
                         self.type_stack.push(Type::Int)
                     }
+                    (Type::Float, Type::Float) => {
+                        self.emit(Node::Operator(Op::SumFloat));
+                        self.type_stack.push(Type::Float)
+                    }
                     (Type::Str, Type::Str) => {
-                        self.ast.push(Node::Operator {
-                            op: Op::ConcatStr,
-                            arity: 2,
-                            func: |args| match (args.get(0), args.get(1)) {
-                                (Some(Value::Str(a)), Some(Value::Str(b))) => {
-                                    Some(vec![Value::Str(a.to_owned() + b)])
-                                }
-                                _ => None,
-                            },
-                        });
+                        self.emit(Node::Operator(Op::ConcatStr));
                         self.type_stack.push(Type::Str)
                     }
                     (_, _) => {
-                        return Err(anyhow!(
-                            "{} operator does not support {:?} and {:?}",
+                        return Err(self.error(format!(
+                            "{} operator does not support {} and {}",
                             word,
                             a,
                             b
-                        ))
+                        )))
                     }
                 }
                 Ok(true)
             }
             "-" => {
-                let b = self.type_stack.pop().unwrap();
-                let a = self.type_stack.pop().unwrap();
-                match (a, b) {
+                let [a, b] = self.pop_args(word)?;
+                match (&a, &b) {
                     (Type::Int, Type::Int) => {
-                        self.ast.push(Node::Operator {
-                            op: Op::SubInt,
-                            arity: 2,
-                            func: |args| match (args.get(0), args.get(1)) {
-                                (Some(Value::Int(a)), Some(Value::Int(b))) => {
-                                    Some(vec![Value::Int(a - b)])
-                                }
-                                _ => None,
-                            },
-                        });
+                        self.emit(Node::Operator(Op::SubInt));
                         self.type_stack.push(Type::Int)
                     }
+                    (Type::Float, Type::Float) => {
+                        self.emit(Node::Operator(Op::SubFloat));
+                        self.type_stack.push(Type::Float)
+                    }
                     (_, _) => {
-                        return Err(anyhow!(
-                            "{} operator does not support {:?} and {:?}",
+                        return Err(self.error(format!(
+                            "{} operator does not support {} and {}",
                             word,
                             a,
                             b
-                        ))
+                        )))
                     }
                 }
                 Ok(true)
             }
             "*" => {
-                let b = self.type_stack.pop().unwrap();
-                let a = self.type_stack.pop().unwrap();
-                match (a, b) {
+                let [a, b] = self.pop_args(word)?;
+                match (&a, &b) {
                     (Type::Int, Type::Int) => {
-                        self.ast.push(Node::Operator {
-                            op: Op::MultInt,
-                            arity: 2,
-                            func: |args| match (args.get(0), args.get(1)) {
-                                (Some(Value::Int(a)), Some(Value::Int(b))) => {
-                                    Some(vec![Value::Int(a * b)])
-                                }
-                                _ => None,
-                            },
-                        });
+                        self.emit(Node::Operator(Op::MultInt));
                         self.type_stack.push(Type::Int)
                     }
+                    (Type::Float, Type::Float) => {
+                        self.emit(Node::Operator(Op::MultFloat));
+                        self.type_stack.push(Type::Float)
+                    }
                     (_, _) => {
-                        return Err(anyhow!(
-                            "{} operator does not support {:?} and {:?}",
+                        return Err(self.error(format!(
+                            "{} operator does not support {} and {}",
                             word,
                             a,
                             b
-                        ))
+                        )))
                     }
                 }
                 Ok(true)
             }
             "/" => {
-                let b = self.type_stack.pop().unwrap();
-                let a = self.type_stack.pop().unwrap();
-                match (a, b) {
+                let [a, b] = self.pop_args(word)?;
+                match (&a, &b) {
                     (Type::Int, Type::Int) => {
-                        self.ast.push(Node::Operator {
-                            op: Op::DivInt,
-                            arity: 2,
-                            func: |args| match (args.get(0), args.get(1)) {
-                                (Some(Value::Int(a)), Some(Value::Int(b))) => {
-                                    Some(vec![Value::Int(a / b)])
-                                }
-                                _ => None,
-                            },
-                        });
+                        self.emit(Node::Operator(Op::DivInt));
                         self.type_stack.push(Type::Int)
                     }
+                    (Type::Float, Type::Float) => {
+                        self.emit(Node::Operator(Op::DivFloat));
+                        self.type_stack.push(Type::Float)
+                    }
                     (_, _) => {
-                        return Err(anyhow!(
-                            "{} operator does not support {:?} and {:?}",
+                        return Err(self.error(format!(
+                            "{} operator does not support {} and {}",
                             word,
                             a,
                             b
-                        ))
+                        )))
                     }
                 }
                 Ok(true)
             }
             "%" => {
-                let b = self.type_stack.pop().unwrap();
-                let a = self.type_stack.pop().unwrap();
-                match (a, b) {
+                let [a, b] = self.pop_args(word)?;
+                match (&a, &b) {
                     (Type::Int, Type::Int) => {
-                        self.ast.push(Node::Operator {
-                            op: Op::ModInt,
-                            arity: 2,
-                            func: |args| match (args.get(0), args.get(1)) {
-                                (Some(Value::Int(a)), Some(Value::Int(b))) => {
-                                    Some(vec![Value::Int(a % b)])
-                                }
-                                _ => None,
-                            },
-                        });
+                        self.emit(Node::Operator(Op::ModInt));
                         self.type_stack.push(Type::Int)
                     }
+                    (Type::Float, Type::Float) => {
+                        self.emit(Node::Operator(Op::ModFloat));
+                        self.type_stack.push(Type::Float)
+                    }
                     (_, _) => {
-                        return Err(anyhow!(
-                            "{} operator does not support {:?} and {:?}",
+                        return Err(self.error(format!(
+                            "{} operator does not support {} and {}",
                             word,
                             a,
                             b
-                        ))
+                        )))
                     }
                 }
                 Ok(true)
             }
             "==" => {
-                let b = self.type_stack.pop().unwrap();
-                let a = self.type_stack.pop().unwrap();
-                match (a, b) {
+                let [a, b] = self.pop_args(word)?;
+                match (&a, &b) {
                     (Type::Int, Type::Int) => {
-                        self.ast.push(Node::Operator {
-                            op: Op::EqInt,
-                            arity: 2,
-                            func: |args| match (args.get(0), args.get(1)) {
-                                (Some(Value::Int(a)), Some(Value::Int(b))) => {
-                                    Some(vec![Value::Bool(a == b)])
-                                }
-                                _ => None,
-                            },
-                        });
+                        self.emit(Node::Operator(Op::EqInt));
+                        self.type_stack.push(Type::Bool)
+                    }
+                    (Type::Float, Type::Float) => {
+                        self.emit(Node::Operator(Op::EqFloat));
                         self.type_stack.push(Type::Bool)
                     }
                     (_, _) => {
-                        return Err(anyhow!(
-                            "{} operator does not support {:?} and {:?}",
+                        return Err(self.error(format!(
+                            "{} operator does not support {} and {}",
                             word,
                             a,
                             b
-                        ))
+                        )))
                     }
                 }
                 Ok(true)
             }
             "<" => {
-                let b = self.type_stack.pop().unwrap();
-                let a = self.type_stack.pop().unwrap();
-                match (a, b) {
+                let [a, b] = self.pop_args(word)?;
+                match (&a, &b) {
                     (Type::Int, Type::Int) => {
-                        self.ast.push(Node::Operator {
-                            op: Op::LtInt,
-                            arity: 2,
-                            func: |args| match (args.get(0), args.get(1)) {
-                                (Some(Value::Int(a)), Some(Value::Int(b))) => {
-                                    Some(vec![Value::Bool(a < b)])
-                                }
-                                _ => None,
-                            },
-                        });
+                        self.emit(Node::Operator(Op::LtInt));
+                        self.type_stack.push(Type::Bool)
+                    }
+                    (Type::Float, Type::Float) => {
+                        self.emit(Node::Operator(Op::LtFloat));
                         self.type_stack.push(Type::Bool)
                     }
                     (_, _) => {
-                        return Err(anyhow!(
-                            "{} operator does not support {:?} and {:?}",
+                        return Err(self.error(format!(
+                            "{} operator does not support {} and {}",
                             word,
                             a,
                             b
-                        ))
+                        )))
                     }
                 }
                 Ok(true)
             }
             ">" => {
-                let b = self.type_stack.pop().unwrap();
-                let a = self.type_stack.pop().unwrap();
-                match (a, b) {
+                let [a, b] = self.pop_args(word)?;
+                match (&a, &b) {
                     (Type::Int, Type::Int) => {
-                        self.ast.push(Node::Operator {
-                            op: Op::GtInt,
-                            arity: 2,
-                            func: |args| match (args.get(0), args.get(1)) {
-                                (Some(Value::Int(a)), Some(Value::Int(b))) => {
-                                    Some(vec![Value::Bool(a > b)])
-                                }
-                                _ => None,
-                            },
-                        });
+                        self.emit(Node::Operator(Op::GtInt));
+                        self.type_stack.push(Type::Bool)
+                    }
+                    (Type::Float, Type::Float) => {
+                        self.emit(Node::Operator(Op::GtFloat));
                         self.type_stack.push(Type::Bool)
                     }
                     (_, _) => {
-                        return Err(anyhow!(
-                            "{} operator does not support {:?} and {:?}",
+                        return Err(self.error(format!(
+                            "{} operator does not support {} and {}",
                             word,
                             a,
                             b
-                        ))
+                        )))
                     }
                 }
                 Ok(true)
             }
             "<=" => {
-                let b = self.type_stack.pop().unwrap();
-                let a = self.type_stack.pop().unwrap();
-                match (a, b) {
+                let [a, b] = self.pop_args(word)?;
+                match (&a, &b) {
                     (Type::Int, Type::Int) => {
-                        self.ast.push(Node::Operator {
-                            op: Op::LqInt,
-                            arity: 2,
-                            func: |args| match (args.get(0), args.get(1)) {
-                                (Some(Value::Int(a)), Some(Value::Int(b))) => {
-                                    Some(vec![Value::Bool(a <= b)])
-                                }
-                                _ => None,
-                            },
-                        });
+                        self.emit(Node::Operator(Op::LqInt));
+                        self.type_stack.push(Type::Bool)
+                    }
+                    (Type::Float, Type::Float) => {
+                        self.emit(Node::Operator(Op::LqFloat));
                         self.type_stack.push(Type::Bool)
                     }
                     (_, _) => {
-                        return Err(anyhow!(
-                            "{} operator does not support {:?} and {:?}",
+                        return Err(self.error(format!(
+                            "{} operator does not support {} and {}",
                             word,
                             a,
                             b
-                        ))
+                        )))
                     }
                 }
                 Ok(true)
             }
             ">=" => {
-                let b = self.type_stack.pop().unwrap();
-                let a = self.type_stack.pop().unwrap();
-                match (a, b) {
+                let [a, b] = self.pop_args(word)?;
+                match (&a, &b) {
                     (Type::Int, Type::Int) => {
-                        self.ast.push(Node::Operator {
-                            op: Op::GqInt,
-                            arity: 2,
-                            func: |args| match (args.get(0), args.get(1)) {
-                                (Some(Value::Int(a)), Some(Value::Int(b))) => {
-                                    Some(vec![Value::Bool(a >= b)])
-                                }
-                                _ => None,
-                            },
-                        });
+                        self.emit(Node::Operator(Op::GqInt));
+                        self.type_stack.push(Type::Bool)
+                    }
+                    (Type::Float, Type::Float) => {
+                        self.emit(Node::Operator(Op::GqFloat));
                         self.type_stack.push(Type::Bool)
                     }
                     (_, _) => {
-                        return Err(anyhow!(
-                            "{} operator does not support {:?} and {:?}",
+                        return Err(self.error(format!(
+                            "{} operator does not support {} and {}",
                             word,
                             a,
                             b
-                        ))
+                        )))
                     }
                 }
                 Ok(true)
             }
             "og" => {
-                let b = self.type_stack.pop().unwrap();
-                let a = self.type_stack.pop().unwrap();
-                match (a, b) {
+                let [a, b] = self.pop_args(word)?;
+                match (&a, &b) {
                     (Type::Bool, Type::Bool) => {
-                        self.ast.push(Node::Operator {
-                            op: Op::AndBool,
-                            arity: 2,
-                            func: |args| match (args.get(0), args.get(1)) {
-                                (Some(Value::Bool(a)), Some(Value::Bool(b))) => {
-                                    Some(vec![Value::Bool(*a && *b)])
-                                }
-                                _ => None,
-                            },
-                        });
+                        self.emit(Node::Operator(Op::AndBool));
                         self.type_stack.push(Type::Bool)
                     }
                     (_, _) => {
-                        return Err(anyhow!(
-                            "{} operator does not support {:?} and {:?}",
+                        return Err(self.error(format!(
+                            "{} operator does not support {} and {}",
                             word,
                             a,
                             b
-                        ))
+                        )))
                     }
                 }
                 Ok(true)
             }
             "dup" => {
-                let Some(b) = self.type_stack.pop() else {
-                    return Err(anyhow!("{} needs ateast 1 argument", word));
-                };
-                self.ast.push(Node::Operator {
-                    op: Op::Dup,
-                    arity: 1,
-                    func: |args| Some(vec![args[0].clone(), args[0].clone()]),
-                });
-                self.type_stack.push(b);
+                let [b] = self.pop_args(word)?;
+                self.emit(Node::Operator(Op::Dup));
+                self.type_stack.push(b.clone());
                 self.type_stack.push(b);
                 Ok(true)
             }
             "slipp" => {
-                let Some(b) = self.type_stack.pop() else {
-                    return Err(anyhow!("{} needs ateast 1 argument", word));
-                };
-                self.ast.push(Node::Operator {
-                    op: Op::Drop,
-                    arity: 1,
-                    func: |_args| None,
-                });
+                let [_] = self.pop_args(word)?;
+                self.emit(Node::Operator(Op::Drop));
+                Ok(true)
+            }
+            "snu" => {
+                let [a, b] = self.pop_args(word)?;
+                self.emit(Node::Operator(Op::Swap));
                 self.type_stack.push(b);
+                self.type_stack.push(a);
+                Ok(true)
+            }
+            "over" => {
+                let [a, b] = self.pop_args(word)?;
+                self.emit(Node::Operator(Op::Over));
+                self.type_stack.push(a.clone());
                 self.type_stack.push(b);
+                self.type_stack.push(a);
                 Ok(true)
             }
-            "snu" => {
-                let b = self.type_stack.pop().unwrap();
-                let a = self.type_stack.pop().unwrap();
-                match (a, b) {
-                    (_, _) => {
-                        self.ast.push(Node::Operator {
-                            op: Op::Swap,
-                            arity: 2,
-                            func: |args| match (args.get(0), args.get(1)) {
-                                (Some(a), Some(b)) => {
-                                    Some(vec![b.clone(), a.clone()])
-                                }
-                                _ => None,
-                            },
-                        });
-                        self.type_stack.push(b);
-                        self.type_stack.push(a);
-                    }
+            "til_ftall" => {
+                let [a] = self.pop_args(word)?;
+                if a != Type::Int {
+                    return Err(self.error(format!("{} operator does not support {}", word, a)));
                 }
+                self.emit(Node::Operator(Op::IntToFloat));
+                self.type_stack.push(Type::Float);
                 Ok(true)
             }
-            "over" => {
-                let b = self.type_stack.pop().unwrap();
-                let a = self.type_stack.pop().unwrap();
-                match (a, b) {
-                    (_, _) => {
-                        self.ast.push(Node::Operator {
-                            op: Op::Over,
-                            arity: 2,
-                            func: |args| match (args.get(0), args.get(1)) {
-                                (Some(a), Some(b)) => {
-                                    Some(vec![a.clone(), b.clone(), a.clone()])
-                                }
-                                _ => None,
-                            },
-                        });
-                        self.type_stack.push(a);
-                        self.type_stack.push(b);
-                        self.type_stack.push(a);
+            "til_htall" => {
+                let [a] = self.pop_args(word)?;
+                if a != Type::Float {
+                    return Err(self.error(format!("{} operator does not support {}", word, a)));
+                }
+                self.emit(Node::Operator(Op::FloatToInt));
+                self.type_stack.push(Type::Int);
+                Ok(true)
+            }
+            "lengde" => {
+                let [a] = self.pop_args(word)?;
+                match (&a, self.last_load()) {
+                    (Type::List(_) | Type::Str, Some((name, slot))) => {
+                        self.ast.pop();
+                        self.emit(Node::LoadLen(name, slot));
                     }
+                    (Type::List(_), None) => self.emit(Node::Operator(Op::ListLen)),
+                    (Type::Str, None) => self.emit(Node::Operator(Op::StrLen)),
+                    _ => return Err(self.error(format!("{} operator does not support {}", word, a))),
+                }
+                self.type_stack.push(Type::Int);
+                Ok(true)
+            }
+            "legg_til" => {
+                let [a, b] = self.pop_args(word)?;
+                if a != Type::List(Box::new(b.clone())) {
+                    return Err(self.error(format!("{} operator does not support {} and {}", word, a, b)));
                 }
+                self.emit(Node::Operator(Op::ListPush));
+                self.type_stack.push(a);
+                Ok(true)
+            }
+            "ta_ut" => {
+                let [a] = self.pop_args(word)?;
+                let Type::List(element) = &a else {
+                    return Err(self.error(format!("{} operator does not support {}", word, a)));
+                };
+                let element = (**element).clone();
+                self.emit(Node::PopList);
+                self.type_stack.push(a);
+                self.type_stack.push(element);
+                Ok(true)
+            }
+            ".." => {
+                let [a, b] = self.pop_args(word)?;
+                if (&a, &b) != (&Type::Int, &Type::Int) {
+                    return Err(self.error(format!("{} operator does not support {} and {}", word, a, b)));
+                }
+                self.emit(Node::Operator(Op::RangeExclusive));
+                self.type_stack.push(Type::Range);
+                Ok(true)
+            }
+            "..=" => {
+                let [a, b] = self.pop_args(word)?;
+                if (&a, &b) != (&Type::Int, &Type::Int) {
+                    return Err(self.error(format!("{} operator does not support {} and {}", word, a, b)));
+                }
+                self.emit(Node::Operator(Op::RangeInclusive));
+                self.type_stack.push(Type::Range);
                 Ok(true)
             }
             "skrivnl" => {
-                let _ = self.type_stack.pop().unwrap();
-                self.ast.push(Node::Operator {
-                    op: Op::Println,
-                    arity: 1,
-                    func: |args| {
-                        println!("{}", args[0]);
-                        None
-                    },
-                });
+                let [_] = self.pop_args(word)?;
+                self.emit(Node::Operator(Op::Println));
+                Ok(true)
+            }
+            "påstå" => {
+                let [a] = self.pop_args(word)?;
+                if a != Type::Bool {
+                    return Err(self.error(format!("{} operator does not support {}", word, a)));
+                }
+                self.emit(Node::Operator(Op::Assert));
+                Ok(true)
+            }
+            "påstå_lik" => {
+                let [a, b] = self.pop_args(word)?;
+                if a != b {
+                    return Err(self.error(format!("{} operator does not support {} and {}", word, a, b)));
+                }
+                self.emit(Node::Operator(Op::AssertEq));
                 Ok(true)
             }
             _ => Ok(false),
         }
     }
 
-    fn parse_identifier(&mut self, word: &String) -> Result<()> {
-        self.ast.push(Node::Identifier(word.clone()));
+    /// Pops the `N` topmost types for `word`, with the deepest value first.
+    fn pop_args<const N: usize>(&mut self, word: &str) -> Result<[Type; N]> {
+        let len = self.type_stack.len();
+        if len < N {
+            return Err(self.error(format!(
+                "operator `{}` needs {} value{} but the stack has {}: {}",
+                word,
+                N,
+                if N == 1 { "" } else { "s" },
+                len,
+                type_list(&self.type_stack)
+            )));
+        }
+        let args = self.type_stack.split_off(len - N);
+        Ok(args.try_into().expect("length was checked above"))
+    }
+
+    /// The variable the last node loads onto the stack, so an instruction that only reads it can
+    /// read it where it is instead of from a copy.
+    fn last_load(&self) -> Option<(String, Slot)> {
+        match self.ast.last() {
+            Some(Ast { kind: AstKind::Op(Node::Load(name, slot)), .. }) => Some((name.clone(), *slot)),
+            _ => None,
+        }
+    }
+
+    fn parse_identifier(&mut self, word: &str) -> Result<()> {
+        let Some(variable) = self.lookup(word) else {
+            return Err(self.error(format!("could not find identifier '{}'", word)));
+        };
+        let (t, slot) = (variable.t.clone(), variable.slot);
+        self.emit(Node::Load(word.to_string(), slot));
+        self.type_stack.push(t);
         Ok(())
     }
 }
+
+
+/// Whether `code` only pushes one new value, without touching what was on the stack before it or
+/// calling anything that could change a variable.
+fn pushes_one_value(code: &[Ast]) -> bool {
+    let mut depth = 0;
+    for ast in code {
+        let (takes, gives) = match &ast.kind {
+            AstKind::Op(Node::PushInt(_) | Node::PushFloat(_) | Node::PushBool(_) | Node::PushStr(_)) => (0, 1),
+            AstKind::Op(Node::Load(..) | Node::LoadLen(..)) => (0, 1),
+            AstKind::Op(Node::LoadIndex(..)) => (1, 1),
+            // the other operators replace their arguments with one value
+            AstKind::Op(Node::Operator(op)) if !matches!(
+                op,
+                Op::Dup | Op::Drop | Op::Swap | Op::Over | Op::Println | Op::Assert | Op::AssertEq
+            ) => {
+                (op.arity(), 1)
+            }
+            _ => return false,
+        };
+        if depth < takes {
+            return false;
+        }
+        depth = depth - takes + gives;
+    }
+    depth == 1
+}
+
+fn is_valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&name)
+        && !BUILTINS.contains(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> (String, Span) {
+        let Err(err) = Parser::parse(source, None) else {
+            panic!("{:?} should not parse", source);
+        };
+        let err = err.downcast::<SourceError>().unwrap();
+        (err.message, err.span)
+    }
+
+    #[test]
+    fn operator_without_enough_values() {
+        let (message, span) = error("1 +");
+        assert_eq!(message, "operator `+` needs 2 values but the stack has 1: [Htall]");
+        assert_eq!(span, Span::new(1, 3));
+    }
+
+    #[test]
+    fn branches_that_leave_different_types() {
+        let (message, span) = error("1\nhvis sann { 2 } ellvis usann { 2.0 } ellers { 3 }");
+        assert_eq!(
+            message,
+            "all branches must leave the same types on the stack: `ellers` leaves [Htall, Htall], \
+             but this branch leaves [Htall, Ftall]"
+        );
+        assert_eq!(span, Span::new(2, 17));
+    }
+
+    #[test]
+    fn if_without_else_that_changes_the_stack() {
+        let (message, span) = error("1\nhvis sann { slipp }");
+        assert_eq!(
+            message,
+            "`hvis` without `ellers` must leave the stack unchanged: expected [Htall], but this branch leaves []"
+        );
+        assert_eq!(span, Span::new(2, 1));
+    }
+
+    #[test]
+    fn while_body_that_grows_the_stack() {
+        let (message, span) = error("\"a\"\nnår sann { 1 }");
+        assert_eq!(
+            message,
+            "the body of `når` must leave the stack unchanged: expected [Streng], but it leaves [Streng, Htall]"
+        );
+        assert_eq!(span, Span::new(2, 1));
+    }
+}
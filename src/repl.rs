@@ -0,0 +1,125 @@
+use ae_skarp::Interpreter;
+use ae_skarp::lexer::{lex, TokenKind};
+use ae_skarp::utils::*;
+use anyhow::Result;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+:stack         show the stack
+:clear         empty the stack
+:ast           show the tree of the last input
+:load fil.æs   run a file
+:help          show this list
+:quit          exit (or Ctrl-D)";
+
+/// Reads code from stdin and runs it line by line. The stack, variables and functions are kept
+/// between inputs, so every input continues where the last one stopped.
+pub fn start() -> Result<()> {
+    let mut repl = Repl {
+        interpreter: Interpreter::new(),
+        last_ast: String::new(),
+    };
+    repl.interpreter.set_optimize(false);
+    println!("æ# REPL, :help for commands");
+
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        let Some(line) = lines.next() else { break };
+        let mut input = line?;
+        while needs_more(&input) {
+            print!(". ");
+            io::stdout().flush()?;
+            let Some(line) = lines.next() else { break };
+            input += "\n";
+            input += &line?;
+        }
+
+        let trimmed = input.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        if let Some(command) = trimmed.strip_prefix(':') {
+            let (name, arg) = command.split_once(' ').unwrap_or((command, ""));
+            match name {
+                "stack" => repl.show_stack(),
+                "clear" => repl.interpreter.clear_stack(),
+                "ast" => print!("{}", repl.last_ast),
+                "load" => match fs::read_to_string(arg.trim()) {
+                    Ok(source) => {
+                        repl.input(&source, arg.trim());
+                        repl.show_stack();
+                    }
+                    Err(err) => eprintln!("error: could not read '{}': {}", arg.trim(), err),
+                },
+                "help" => println!("{}", HELP),
+                "quit" => break,
+                _ => eprintln!("unknown command ':{}', :help lists the commands", name),
+            }
+            continue;
+        }
+
+        repl.input(&input, "<repl>");
+        repl.show_stack();
+    }
+    println!();
+    Ok(())
+}
+
+struct Repl {
+    interpreter: Interpreter,
+    last_ast: String,
+}
+
+impl Repl {
+    /// Runs `source` and prints any error.
+    fn input(&mut self, source: &str, file: &str) {
+        if let Err(err) = self.run(source) {
+            match render_error(&err, file, source) {
+                Some(rendered) => eprint!("{}", rendered),
+                None => eprintln!("error: {}", err),
+            }
+        }
+    }
+
+    fn run(&mut self, source: &str) -> Result<()> {
+        let program = self.interpreter.parse(source)?;
+        self.last_ast = program.tree().to_string();
+        self.interpreter.run(program)
+    }
+
+    fn show_stack(&self) {
+        let values: Vec<String> = self
+            .interpreter
+            .stack()
+            .iter()
+            .zip(self.interpreter.stack_types())
+            .map(|(value, t)| format!("{}: {}", value.repr(), t))
+            .collect();
+        println!("[{}]", values.join(", "));
+    }
+}
+
+/// Whether `input` is the start of a block that continues on the next line: a `{` without its `}`,
+/// or a `:` at the end of a line, which goes on until an empty line.
+fn needs_more(input: &str) -> bool {
+    let Ok(lexed) = lex(input) else {
+        return false;
+    };
+    let tokens = lexed.tokens;
+    let open = tokens.iter().filter(|t| t.kind == TokenKind::OpenBrace).count();
+    let close = tokens.iter().filter(|t| t.kind == TokenKind::CloseBrace).count();
+    if open > close {
+        return true;
+    }
+    let colon_block = tokens
+        .windows(2)
+        .any(|pair| {
+            pair[0].kind == TokenKind::Colon
+                && (pair[1].kind == TokenKind::Eof || pair[1].span.line > pair[0].span.line)
+        });
+    let ended = input.ends_with('\n') || input.lines().last().is_some_and(|line| line.trim().is_empty());
+    colon_block && !ended
+}
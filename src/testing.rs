@@ -0,0 +1,126 @@
+use ae_skarp::ast::{Ast, AstKind};
+use ae_skarp::eval;
+use ae_skarp::lower;
+use ae_skarp::optimize;
+use ae_skarp::parser::Parser;
+use ae_skarp::utils::*;
+use anyhow::Result;
+use std::cell::RefCell;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Runs `ae-skarp test`. Every `test` block in the files, and in the `.æs` files in the folders, is
+/// run in a fresh `Runtime` after the rest of its file, so it can use the variables and functions
+/// defined there. What a test writes is only shown if it fails. Returns whether every test passed.
+pub fn run_tests(paths: &[String], optimize: bool) -> Result<bool> {
+    let mut files = vec![];
+    if paths.is_empty() {
+        find_files(Path::new("."), &mut files)?;
+    }
+    for path in paths {
+        find_files(Path::new(path), &mut files)?;
+    }
+
+    let (mut passed, mut failed) = (0, 0);
+    for file in files {
+        let name = file.display().to_string();
+        let source = fs::read_to_string(&file)?;
+        let parser = match Parser::parse(&source, None) {
+            Ok(parser) => parser,
+            Err(err) => {
+                println!("could not parse {}", name);
+                report(&err, &name, &source);
+                failed += 1;
+                continue;
+            }
+        };
+
+        let (tests, program): (Vec<Ast>, Vec<Ast>) =
+            parser.ast.into_iter().partition(|node| matches!(node.kind, AstKind::Test { .. }));
+        if tests.is_empty() {
+            continue;
+        }
+        println!("running {} test{} in {}", tests.len(), if tests.len() == 1 { "" } else { "s" }, name);
+        let program = lower::lower(program);
+        for test in tests {
+            let AstKind::Test { name: test_name, body } = test.kind else {
+                unreachable!("only tests were picked out")
+            };
+            let mut code = program.clone();
+            code.extend(lower::lower(body));
+            if optimize {
+                code = optimize::optimize(code);
+            }
+
+            let output = Output::default();
+            match eval::run(&mut Runtime::with_output(Box::new(output.clone())), &code) {
+                Ok(()) => {
+                    println!("test {} ... ok", test_name);
+                    passed += 1;
+                }
+                Err(err) => {
+                    println!("test {} ... FAILED", test_name);
+                    report(&err, &name, &source);
+                    let output = output.0.borrow();
+                    if !output.is_empty() {
+                        println!("---- output of {} ----", test_name);
+                        print!("{}", String::from_utf8_lossy(&output));
+                    }
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    println!();
+    let result = if failed == 0 { "ok" } else { "FAILED" };
+    println!("test result: {}. {} passed; {} failed", result, passed, failed);
+    Ok(failed == 0)
+}
+
+/// Adds `path` if it is a file, or every `.æs` file under it if it is a folder.
+fn find_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+    for entry in entries {
+        let hidden = entry
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.') || name == "target");
+        if entry.is_dir() && !hidden {
+            find_files(&entry, files)?;
+        } else if entry.extension().is_some_and(|extension| extension == "æs") {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+fn report(err: &anyhow::Error, file: &str, source: &str) {
+    match render_error(err, file, source) {
+        Some(rendered) => print!("{}", rendered),
+        None => println!("error: {}", err),
+    }
+}
+
+/// What a test wrote with `skrivnl`.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use ae_skarp::eval::Inspector;
+use ae_skarp::utils::*;
+use anyhow::Result;
+use std::fmt::Write as _;
+use std::io::Write;
+
+/// Writes a line for every instruction that runs, for `--trace`. Every line has the position of
+/// the instruction, the instruction itself, and the stack before and after it. With `json` every
+/// line is a JSON object, which makes it easy to diff two runs or read them with other tools. An
+/// instruction that fails gets a line with the error instead of the stack after it.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    json: bool,
+    stack_before: Vec<Value>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, json: bool) -> Tracer {
+        Tracer {
+            out,
+            json,
+            stack_before: vec![],
+        }
+    }
+}
+
+impl Inspector for Tracer {
+    fn before(&mut self, rt: &Runtime, _code: &[Instr]) -> Result<()> {
+        self.stack_before.clone_from(&rt.stack);
+        Ok(())
+    }
+
+    fn after(&mut self, rt: &Runtime, code: &[Instr], index: usize) -> Result<()> {
+        self.write_line(&code[index], index, Ok(&rt.stack))
+    }
+
+    fn failed(&mut self, _rt: &Runtime, code: &[Instr], index: usize, err: &anyhow::Error) -> Result<()> {
+        self.write_line(&code[index], index, Err(err))
+    }
+}
+
+impl Tracer {
+    /// Writes the line for the instruction at `index`, which either left `after` on the stack or
+    /// failed with an error.
+    fn write_line(&mut self, instr: &Instr, index: usize, after: Result<&[Value], &anyhow::Error>) -> Result<()> {
+        // the line already has the position, so only the message of a runtime error is needed
+        let after = after.map_err(|err| match err.downcast_ref::<RuntimeError>() {
+            Some(err) => err.kind.to_string(),
+            None => err.to_string(),
+        });
+        let line = if self.json {
+            let mut line = format!(
+                "{{\"op_counter\":{},\"node\":{},\"span\":\"{}\",\"before\":",
+                index,
+                json_string(&format!("{:?}", instr.node)),
+                instr.span
+            );
+            json_list(&mut line, &self.stack_before);
+            match after {
+                Ok(stack) => {
+                    line += ",\"after\":";
+                    json_list(&mut line, stack);
+                }
+                Err(err) => {
+                    line += ",\"error\":";
+                    line += &json_string(&err);
+                }
+            }
+            line + "}"
+        } else {
+            let after = match after {
+                Ok(stack) => repr_list(stack),
+                Err(err) => format!("error: {}", err),
+            };
+            format!(
+                "{}: {:?} ({}) {} -> {}",
+                index,
+                instr.node,
+                instr.span,
+                repr_list(&self.stack_before),
+                after
+            )
+        };
+        writeln!(self.out, "{}", line)?;
+        Ok(())
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+fn json_list(out: &mut String, values: &[Value]) {
+    out.push('[');
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        json_value(out, value);
+    }
+    out.push(']');
+}
+
+fn json_value(out: &mut String, value: &Value) {
+    match value {
+        Value::Int(x) => write!(out, "{}", x).unwrap(),
+        // JSON has no NaN or infinity
+        Value::Float(x) if x.is_finite() => write!(out, "{:?}", x).unwrap(),
+        Value::Float(x) => out.push_str(&json_string(&x.to_string())),
+        Value::Bool(x) => write!(out, "{}", x).unwrap(),
+        Value::Str(x) => out.push_str(&json_string(x)),
+        Value::List(values) => json_list(out, values),
+        Value::Range(..) | Value::Null => out.push_str(&json_string(&value.to_string())),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -1,6 +1,182 @@
-use std::{fmt, collections::HashMap};
+use std::{fmt, collections::HashMap, error::Error};
+use std::io::{self, Write};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, col: usize) -> Span {
+        Span { line, col }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// A comment from the source code. Comments are not part of the tokens, but are kept so tools like a
+/// formatter can put them back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comment {
+    pub text: String,
+    pub span: Span,
+    /// Whether this is a `#[ .. ]#` block comment rather than a `#` line comment.
+    pub block: bool,
+}
+
+/// An error that points at a position in the source code.
 #[derive(Debug)]
+pub struct SourceError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl SourceError {
+    pub fn at(span: Span, message: impl Into<String>) -> anyhow::Error {
+        anyhow::Error::new(SourceError {
+            span,
+            message: message.into(),
+        })
+    }
+
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+impl Error for SourceError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeErrorKind {
+    StackUnderflow { op: String, needed: usize, found: usize },
+    ExpectedBool { found: Option<Value> },
+    UnknownIdentifier(String),
+    UnknownFunction(String),
+    ReturnOutsideFunction,
+    IndexOutOfBounds { index: i64, len: usize },
+    PopFromEmptyList,
+    AssertionFailed,
+    NotEqual { left: Value, right: Value },
+    Output(String),
+    HostFunction { name: String, message: String },
+    DivisionByZero,
+    IntegerOverflow,
+    WrongType { op: String, found: Vec<Value> },
+    LocalOutsideFunction(String),
+    ListTooLong(usize),
+    InstructionLimit(u64),
+    StackLimit(usize),
+    MemoryLimit(usize),
+    CallDepthLimit(usize),
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeErrorKind::StackUnderflow { op, needed, found } => write!(
+                f,
+                "`{}` needs {} value{} but the stack has {}",
+                op,
+                needed,
+                if *needed == 1 { "" } else { "s" },
+                found
+            ),
+            RuntimeErrorKind::ExpectedBool { found: Some(value) } => {
+                write!(f, "expected a bool on the stack, but found '{}'", value)
+            }
+            RuntimeErrorKind::ExpectedBool { found: None } => {
+                write!(f, "expected a bool on the stack, but the stack is empty")
+            }
+            RuntimeErrorKind::UnknownIdentifier(name) => {
+                write!(f, "could not find identifier '{}'", name)
+            }
+            RuntimeErrorKind::UnknownFunction(name) => write!(f, "could not find function '{}'", name),
+            RuntimeErrorKind::ReturnOutsideFunction => write!(f, "return outside of function"),
+            RuntimeErrorKind::IndexOutOfBounds { index, len } => {
+                write!(f, "index {} is out of bounds for length {}", index, len)
+            }
+            RuntimeErrorKind::PopFromEmptyList => write!(f, "cannot take a value out of an empty list"),
+            RuntimeErrorKind::AssertionFailed => write!(f, "assertion failed"),
+            RuntimeErrorKind::NotEqual { left, right } => {
+                write!(f, "assertion failed: {} is not equal to {}", left.repr(), right.repr())
+            }
+            RuntimeErrorKind::Output(err) => write!(f, "could not write the output: {}", err),
+            RuntimeErrorKind::HostFunction { name, message } => write!(f, "'{}' failed: {}", name, message),
+            RuntimeErrorKind::DivisionByZero => write!(f, "division by zero"),
+            RuntimeErrorKind::IntegerOverflow => write!(f, "the result does not fit in an Htall"),
+            RuntimeErrorKind::WrongType { op, found } => {
+                write!(f, "`{}` can not be used on {}", op, repr_list(found))
+            }
+            RuntimeErrorKind::LocalOutsideFunction(name) => {
+                write!(f, "'{}' is a local variable, but no function is running", name)
+            }
+            RuntimeErrorKind::ListTooLong(len) => write!(f, "a list of {} values does not fit in memory", len),
+            RuntimeErrorKind::InstructionLimit(max) => write!(f, "stopped after running {} instructions", max),
+            RuntimeErrorKind::StackLimit(max) => write!(f, "the stack grew past {} values", max),
+            RuntimeErrorKind::MemoryLimit(max) => write!(f, "strings and lists grew past {} bytes", max),
+            RuntimeErrorKind::CallDepthLimit(max) => write!(f, "functions were called more than {} deep", max),
+        }
+    }
+}
+
+/// An error raised by `eval`, together with the position of the node that caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub span: Span,
+    pub kind: RuntimeErrorKind,
+}
+
+impl RuntimeError {
+    pub fn at(span: Span, kind: RuntimeErrorKind) -> anyhow::Error {
+        anyhow::Error::new(RuntimeError { span, kind })
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.kind)
+    }
+}
+
+impl Error for RuntimeError {}
+
+/// Formats a `SourceError` or `RuntimeError` together with the offending source line and a caret
+/// under the column. Returns `None` for errors without a position.
+pub fn render_error(err: &anyhow::Error, file: &str, source: &str) -> Option<String> {
+    let (span, message) = if let Some(e) = err.downcast_ref::<SourceError>() {
+        (e.span, e.message.clone())
+    } else if let Some(e) = err.downcast_ref::<RuntimeError>() {
+        (e.span, e.kind.to_string())
+    } else {
+        return None;
+    };
+
+    let mut out = format!("error: {}\n --> {}:{}\n", message, file, span);
+    // spans from a bytecode file are not checked, so line and column 0 must not underflow
+    if let Some(line) = span.line.checked_sub(1).and_then(|i| source.lines().nth(i)) {
+        let number = span.line.to_string();
+        let padding = " ".repeat(number.len());
+        let caret_indent: String = line
+            .chars()
+            .take(span.col.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        out += &format!("{} |\n", padding);
+        out += &format!("{} | {}\n", number, line);
+        out += &format!("{} | {}^\n", padding, caret_indent);
+    }
+    Some(out)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Op {
     SumInt,
     SubInt,
@@ -8,6 +184,12 @@ pub enum Op {
     DivInt,
     ModInt,
 
+    SumFloat,
+    SubFloat,
+    MultFloat,
+    DivFloat,
+    ModFloat,
+
     ConcatStr,
 
     EqInt,
@@ -15,16 +197,120 @@ pub enum Op {
     GtInt,
     LqInt,
     GqInt,
+    EqFloat,
+    LtFloat,
+    GtFloat,
+    LqFloat,
+    GqFloat,
     AndBool,
 
+    IntToFloat,
+    FloatToInt,
+
+    ListLen,
+    StrLen,
+    ListPush,
+
+    RangeExclusive,
+    RangeInclusive,
+
     Dup,
     Drop,
     Swap,
     Over,
 
     Println,
+
+    Assert,
+    AssertEq,
+}
+
+impl Op {
+    /// Every operator, in the order of their numbers in the bytecode format.
+    pub const ALL: [Op; 36] = [
+        Op::SumInt,
+        Op::SubInt,
+        Op::MultInt,
+        Op::DivInt,
+        Op::ModInt,
+        Op::SumFloat,
+        Op::SubFloat,
+        Op::MultFloat,
+        Op::DivFloat,
+        Op::ModFloat,
+        Op::ConcatStr,
+        Op::EqInt,
+        Op::LtInt,
+        Op::GtInt,
+        Op::LqInt,
+        Op::GqInt,
+        Op::EqFloat,
+        Op::LtFloat,
+        Op::GtFloat,
+        Op::LqFloat,
+        Op::GqFloat,
+        Op::AndBool,
+        Op::IntToFloat,
+        Op::FloatToInt,
+        Op::ListLen,
+        Op::StrLen,
+        Op::ListPush,
+        Op::RangeExclusive,
+        Op::RangeInclusive,
+        Op::Dup,
+        Op::Drop,
+        Op::Swap,
+        Op::Over,
+        Op::Println,
+        Op::Assert,
+        Op::AssertEq,
+    ];
+
+    /// How many values the operator takes off the stack.
+    pub fn arity(self) -> usize {
+        match self {
+            Op::SumInt => 2,
+            Op::SubInt => 2,
+            Op::MultInt => 2,
+            Op::DivInt => 2,
+            Op::ModInt => 2,
+            Op::SumFloat => 2,
+            Op::SubFloat => 2,
+            Op::MultFloat => 2,
+            Op::DivFloat => 2,
+            Op::ModFloat => 2,
+            Op::ConcatStr => 2,
+            Op::EqInt => 2,
+            Op::LtInt => 2,
+            Op::GtInt => 2,
+            Op::LqInt => 2,
+            Op::GqInt => 2,
+            Op::EqFloat => 2,
+            Op::LtFloat => 2,
+            Op::GtFloat => 2,
+            Op::LqFloat => 2,
+            Op::GqFloat => 2,
+            Op::AndBool => 2,
+            Op::IntToFloat => 1,
+            Op::FloatToInt => 1,
+            Op::ListLen => 1,
+            Op::StrLen => 1,
+            Op::ListPush => 2,
+            Op::RangeExclusive => 2,
+            Op::RangeInclusive => 2,
+            Op::Dup => 1,
+            Op::Drop => 1,
+            Op::Swap => 2,
+            Op::Over => 2,
+            Op::Println => 1,
+            Op::Assert => 1,
+            Op::AssertEq => 2,
+        }
+    }
 }
 
+
+#[derive(Clone)]
 pub struct JumpPointer {
     offset: isize,
 }
@@ -34,12 +320,14 @@ impl JumpPointer {
         JumpPointer { offset }
     }
 
+    pub fn offset(&self) -> isize {
+        self.offset
+    }
+
+    /// The position to jump to, one before the instruction that runs next. A jump to the first
+    /// instruction wraps around to `usize::MAX`, which comes back to 0 when the counter moves on.
     pub fn resolve(&self, current_pos: usize) -> usize {
-        if self.offset >= 0 {
-            current_pos + self.offset as usize
-        } else {
-            current_pos - (-self.offset) as usize
-        }
+        current_pos.wrapping_add_signed(self.offset)
     }
 }
 
@@ -50,57 +338,200 @@ impl fmt::Display for JumpPointer {
     }
 }
 
+#[derive(Clone)]
 pub enum Node {
     PushInt(i64),
+    PushFloat(f64),
     PushBool(bool),
     PushStr(String),
-    Operator {
-        op: Op,
-        arity: usize,
-        func: fn(&[Value]) -> Option<Vec<Value>>,
-    },
-    Identifier(String),
+    Operator(Op),
+    Load(String, Slot),
     Jump(JumpPointer),
     JumpIfFalse(JumpPointer),
     EndOfIf,
-    DefineConst(String),
-    Return(String),
+    Store(String, Slot),
+    Function(String, JumpPointer),
+    Call(String),
+    /// Calls a function registered from Rust with `Interpreter::register`.
+    CallHost(String),
+    FuncReturn,
+    MakeList(usize),
+    RepeatList(usize),
+    Index,
+    /// Reads `name[i]` without copying the list first, with the index on top of the stack.
+    LoadIndex(String, Slot),
+    /// Pushes the length of the list or string in a variable, without copying it first.
+    LoadLen(String, Slot),
+    /// `navn = navn verdi legg_til`, which adds the value to the list in the variable in place.
+    Append(String, Slot),
+    SetIndex(String, Slot),
+    PopList,
+    IterStart,
+    IterNext(String, Slot, JumpPointer),
+    /// Empties the slot of a variable when its scope ends, so the value is not kept alive.
+    Clear(String, Slot),
+}
+
+#[derive(Clone)]
+pub struct Instr {
+    pub node: Node,
+    pub span: Span,
+}
+
+impl Node {
+    /// The pointer of the instructions that jump.
+    pub fn pointer(&self) -> Option<&JumpPointer> {
+        match self {
+            Node::Jump(pointer)
+            | Node::JumpIfFalse(pointer)
+            | Node::Function(_, pointer)
+            | Node::IterNext(_, _, pointer) => Some(pointer),
+            _ => None,
+        }
+    }
+
+    /// The variable slot of the instructions that read or write a variable.
+    pub fn slot(&self) -> Option<Slot> {
+        match self {
+            Node::Load(_, slot)
+            | Node::Store(_, slot)
+            | Node::LoadIndex(_, slot)
+            | Node::LoadLen(_, slot)
+            | Node::Append(_, slot)
+            | Node::SetIndex(_, slot)
+            | Node::IterNext(_, slot, _)
+            | Node::Clear(_, slot) => Some(*slot),
+            _ => None,
+        }
+    }
+
+    pub fn pointer_mut(&mut self) -> Option<&mut JumpPointer> {
+        match self {
+            Node::Jump(pointer)
+            | Node::JumpIfFalse(pointer)
+            | Node::Function(_, pointer)
+            | Node::IterNext(_, _, pointer) => Some(pointer),
+            _ => None,
+        }
+    }
+}
+
+impl Instr {
+    pub fn new(node: Node, span: Span) -> Instr {
+        Instr { node, span }
+    }
+}
+
+impl fmt::Debug for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.node)
+    }
 }
 
 impl fmt::Debug for Node {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Node::PushInt(x) => write!(f, "{}", x),
+            Node::PushFloat(x) => write!(f, "{:?}", x),
             Node::PushBool(x) => write!(f, "{}", x),
             Node::PushStr(x) => write!(f, "\"{}\"", x),
-            Node::Operator {
-                op,
-                arity: _,
-                func: _,
-            } => write!(f, "{:?}", op),
-            Node::Identifier(x) => write!(f, "{}", x),
+            Node::Operator(op) => write!(f, "{:?}", op),
+            Node::Load(name, slot) => write!(f, "{}@{}", name, slot),
             Node::Jump(x) => write!(f, "Jmp({})", x),
             Node::JumpIfFalse(x) => write!(f, "Jnt({})", x),
             Node::EndOfIf => write!(f, "EndOfIf"),
-            Node::DefineConst(x) => write!(f, "DefConst({})", x),
-            Node::Return(_) =>write!(f, "Ret"),
+            Node::Store(name, slot) => write!(f, "Store({}@{})", name, slot),
+            Node::Function(name, x) => write!(f, "Funk({}, {})", name, x),
+            Node::Call(name) => write!(f, "Call({})", name),
+            Node::CallHost(name) => write!(f, "CallHost({})", name),
+            Node::FuncReturn => write!(f, "FnRet"),
+            Node::MakeList(n) => write!(f, "MakeList({})", n),
+            Node::RepeatList(n) => write!(f, "RepeatList({})", n),
+            Node::Index => write!(f, "Index"),
+            Node::LoadIndex(name, slot) => write!(f, "LoadIndex({}@{})", name, slot),
+            Node::LoadLen(name, slot) => write!(f, "LoadLen({}@{})", name, slot),
+            Node::Append(name, slot) => write!(f, "Append({}@{})", name, slot),
+            Node::SetIndex(name, slot) => write!(f, "SetIndex({}@{})", name, slot),
+            Node::PopList => write!(f, "PopList"),
+            Node::IterStart => write!(f, "IterStart"),
+            Node::IterNext(name, slot, x) => write!(f, "IterNext({}@{}, {})", name, slot, x),
+            Node::Clear(name, slot) => write!(f, "Clear({}@{})", name, slot),
         }?;
         Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Null,
     Int(i64),
+    Float(f64),
     Bool(bool),
     Str(String),
+    List(Vec<Value>),
+    Range(i64, i64),
+}
+
+impl Value {
+    /// The type of the value, or `None` when it can not be known, like for an empty list.
+    pub fn type_of(&self) -> Option<Type> {
+        match self {
+            Value::Int(_) => Some(Type::Int),
+            Value::Float(_) => Some(Type::Float),
+            Value::Bool(_) => Some(Type::Bool),
+            Value::Str(_) => Some(Type::Str),
+            Value::Range(..) => Some(Type::Range),
+            Value::List(values) => {
+                let element = values.first()?.type_of()?;
+                values
+                    .iter()
+                    .all(|value| value.type_of().as_ref() == Some(&element))
+                    .then(|| Type::List(Box::new(element)))
+            }
+            Value::Null => None,
+        }
+    }
+
+    /// Whether the value can be used where `t` is expected. An empty list fits any list type.
+    pub fn is(&self, t: &Type) -> bool {
+        match (self, t) {
+            (Value::List(values), Type::List(element)) => values.iter().all(|value| value.is(element)),
+            _ => self.type_of().as_ref() == Some(t),
+        }
+    }
+
+    /// The bytes the value uses outside of itself, for `Limits::memory`. Only strings and lists have
+    /// any.
+    pub fn heap_size(&self) -> usize {
+        match self {
+            Value::Str(s) => s.len(),
+            Value::List(values) => {
+                values.len() * std::mem::size_of::<Value>() + values.iter().map(Value::heap_size).sum::<usize>()
+            }
+            _ => 0,
+        }
+    }
+
+    /// Shows the value the way it is written in code, so strings get their quotes.
+    pub fn repr(&self) -> String {
+        match self {
+            Value::Str(s) => format!("\"{}\"", s),
+            _ => self.to_string(),
+        }
+    }
+}
+
+/// Shows a stack like `[1, "to", 3.0]`.
+pub fn repr_list(values: &[Value]) -> String {
+    let values: Vec<String> = values.iter().map(Value::repr).collect();
+    format!("[{}]", values.join(", "))
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Value::Int(n) => write!(f, "{}", n),
+            Value::Float(n) => write!(f, "{:?}", n),
             Value::Bool(b) => {
                 if *b {
                     write!(f, "sann")
@@ -109,20 +540,226 @@ impl fmt::Display for Value {
                 }
             },
             Value::Str(s) => write!(f, "{}", s),
+            Value::List(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match value {
+                        Value::Str(s) => write!(f, "\"{}\"", s)?,
+                        _ => write!(f, "{}", value)?,
+                    }
+                }
+                write!(f, "]")
+            }
+            Value::Range(start, end) => write!(f, "{}..{}", start, end),
             Value::Null => write!(f, "null"),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Type {
     Int,
+    Float,
     Bool,
-    Str
+    Str,
+    List(Box<Type>),
+    Range,
+}
+
+impl Type {
+    /// Parses a type name. Both `[T; n]` and `Liste<T>` name a list of `T`, the length is only
+    /// used when creating a list from the type.
+    pub fn from_name(name: &str) -> Option<Type> {
+        let name = name.trim();
+        if let Some(inner) = name.strip_prefix("Liste<").and_then(|n| n.strip_suffix('>')) {
+            return Some(Type::List(Box::new(Type::from_name(inner)?)));
+        }
+        if let Some(inner) = name.strip_prefix('[').and_then(|n| n.strip_suffix(']')) {
+            let (element, len) = inner.rsplit_once(';')?;
+            len.trim().parse::<usize>().ok()?;
+            return Some(Type::List(Box::new(Type::from_name(element)?)));
+        }
+        match name {
+            "Htall" => Some(Type::Int),
+            "Ftall" => Some(Type::Float),
+            "Bool" => Some(Type::Bool),
+            "Streng" => Some(Type::Str),
+            "Område" => Some(Type::Range),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "Htall"),
+            Type::Float => write!(f, "Ftall"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Str => write!(f, "Streng"),
+            Type::List(t) => write!(f, "Liste<{}>", t),
+            Type::Range => write!(f, "Område"),
+        }
+    }
+}
+
+pub fn type_list(types: &[Type]) -> String {
+    let names = types.iter().map(|t| t.to_string()).collect::<Vec<String>>();
+    format!("[{}]", names.join(", "))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub inputs: Vec<Type>,
+    pub outputs: Vec<Type>,
+}
+
+/// Limits for running code that can not be trusted, like scripts sent in by users. Going past one
+/// stops the program with its own `RuntimeErrorKind`, at the instruction where it happened. `None`
+/// means no limit, which is the default.
+///
+/// ```
+/// use ae_skarp::{Interpreter, Limits, RuntimeError, RuntimeErrorKind};
+///
+/// let mut interpreter = Interpreter::new();
+/// interpreter.set_limits(Limits { instructions: Some(1000), ..Limits::default() });
+/// let err = interpreter.eval("når sann { }").unwrap_err();
+/// let err = err.downcast_ref::<RuntimeError>().unwrap();
+/// assert_eq!(err.kind, RuntimeErrorKind::InstructionLimit(1000));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// How many instructions one run may execute.
+    pub instructions: Option<u64>,
+    /// How many values the stack may hold.
+    pub stack_depth: Option<usize>,
+    /// How many bytes the strings and lists on the stack and in variables may use together.
+    pub memory: Option<usize>,
+    /// How deep functions may call each other.
+    pub call_depth: Option<usize>,
+}
+
+/// A Rust function that æ# code can call. It takes the values its signature asks for, with the
+/// deepest value first, and returns the values its signature promises. Anything the function needs
+/// from the program that embeds it, like a database connection, it keeps in its closure.
+pub struct HostFunction {
+    pub signature: Signature,
+    pub func: Box<dyn FnMut(Vec<Value>) -> anyhow::Result<Vec<Value>>>,
+}
+
+/// The state of a running `for hver` loop.
+pub struct LoopIter {
+    values: Value,
+    next: usize,
+}
+
+impl LoopIter {
+    pub fn new(iterable: Value) -> LoopIter {
+        let values = match iterable {
+            Value::Str(s) => Value::List(s.chars().map(|c| Value::Str(c.to_string())).collect()),
+            values => values,
+        };
+        LoopIter { values, next: 0 }
+    }
+
+    /// The bytes used by the values left to loop over, for `Limits::memory`.
+    pub fn heap_size(&self) -> usize {
+        self.values.heap_size()
+    }
+}
+
+impl Iterator for LoopIter {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        let value = match &self.values {
+            Value::Range(start, end) => {
+                let n = start.checked_add(self.next as i64)?;
+                (n < *end).then_some(Value::Int(n))
+            }
+            Value::List(values) => values.get(self.next).cloned(),
+            _ => None,
+        };
+        self.next += 1;
+        value
+    }
+}
+
+/// Where a variable lives at runtime. Global slots index `Runtime::mem`, local slots index the
+/// frame of the function call that is currently running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    Global(usize),
+    Local(usize),
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Slot::Global(i) => write!(f, "g{}", i),
+            Slot::Local(i) => write!(f, "l{}", i),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variable {
+    pub t: Type,
+    pub mutable: bool,
+    pub slot: Slot,
 }
 
 pub struct Runtime {
     pub stack: Vec<Value>,
-    pub mem: HashMap<String, Value>,
+    pub mem: Vec<Value>,
+    pub frames: Vec<Vec<Value>>,
+    pub functions: HashMap<String, usize>,
+    pub return_stack: Vec<usize>,
+    pub iterators: Vec<LoopIter>,
     pub op_counter: usize,
+    /// Where `skrivnl` writes to.
+    pub output: Box<dyn Write>,
+    pub host_functions: HashMap<String, HostFunction>,
+    pub limits: Limits,
+    /// The bytes used by strings and lists, for `Limits::memory`. `eval` adds to it when it makes a
+    /// value and subtracts when it drops one, so it never has to count everything again.
+    pub memory: usize,
+}
+
+impl Runtime {
+    pub fn new() -> Runtime {
+        Runtime::with_output(Box::new(io::stdout()))
+    }
+
+    pub fn with_output(output: Box<dyn Write>) -> Runtime {
+        Runtime {
+            stack: vec![],
+            mem: vec![],
+            frames: vec![],
+            functions: HashMap::new(),
+            return_stack: vec![],
+            iterators: vec![],
+            op_counter: 0,
+            output,
+            host_functions: HashMap::new(),
+            limits: Limits::default(),
+            memory: 0,
+        }
+    }
+
+    /// Counts the bytes used by the strings and lists on the stack, in variables and in running
+    /// loops from scratch. Only needed after changing the runtime from outside of `eval`.
+    pub fn count_memory(&self) -> usize {
+        let values = self.stack.iter().chain(&self.mem).chain(self.frames.iter().flatten());
+        values.map(Value::heap_size).sum::<usize>() + self.iterators.iter().map(LoopIter::heap_size).sum::<usize>()
+    }
+}
+
+impl Default for Runtime {
+    fn default() -> Runtime {
+        Runtime::new()
+    }
 }
@@ -0,0 +1,63 @@
+//! Runs every program in `eksempler/` and compares what it prints with the `.forventet` file next
+//! to it. After changing the output on purpose, write the files again with
+//!
+//! ```text
+//! AE_SKARP_REGENERATE=1 cargo test
+//! ```
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn eksempler_print_what_is_expected() {
+    let regenerate = env::var_os("AE_SKARP_REGENERATE").is_some();
+    let mut programs: Vec<_> = fs::read_dir("eksempler")
+        .expect("could not read eksempler/")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "æs"))
+        .collect();
+    programs.sort();
+    assert!(!programs.is_empty(), "found no programs in eksempler/");
+
+    let mut failures = vec![];
+    for program in &programs {
+        let output = run(program);
+        let expected_path = program.with_extension("forventet");
+        if regenerate {
+            fs::write(&expected_path, &output).expect("could not write the .forventet file");
+            continue;
+        }
+
+        match fs::read_to_string(&expected_path) {
+            Ok(expected) if expected == output => {}
+            Ok(expected) => failures.push(format!(
+                "{} printed:\n{}\nbut {} expects:\n{}",
+                program.display(),
+                output,
+                expected_path.display(),
+                expected
+            )),
+            Err(_) => failures.push(format!(
+                "{} is missing, run with AE_SKARP_REGENERATE=1 to create it",
+                expected_path.display()
+            )),
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+fn run(program: &Path) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_ae-skarp"))
+        .arg(program)
+        .output()
+        .expect("could not run ae-skarp");
+    assert!(
+        output.status.success(),
+        "{} failed:\n{}",
+        program.display(),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).expect("the output is not UTF-8")
+}